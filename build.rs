@@ -0,0 +1,107 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const SVD_PATH: &str = "STM32L496.svd";
+const AF_PATH: &str = "data/stm32l496_af.csv";
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed={SVD_PATH}");
+    println!("cargo:rerun-if-changed={AF_PATH}");
+
+    let peripherals = svd_peripherals(&fs::read_to_string(SVD_PATH).expect("Failed to read the SVD"));
+    let rows = fs::read_to_string(AF_PATH).expect("Failed to read the AF table");
+
+    let mut signals: Vec<(String, String)> = Vec::new();
+    let mut entries = Vec::new();
+    let mut seen = HashSet::new();
+
+    for (num, line) in rows.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let at = |msg: &str| -> ! { panic!("{AF_PATH}:{}: {msg}: `{line}`", num + 1) };
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [port, pin, af, peripheral, signal] = fields[..] else {
+            at("expected `port,pin,af,peripheral,signal`");
+        };
+
+        if !peripherals.contains(&format!("GPIO{port}")) {
+            at("unknown GPIO port");
+        }
+        let pin: u8 = pin.parse().unwrap_or_else(|_| at("bad pin number"));
+        let af: u8 = af.parse().unwrap_or_else(|_| at("bad alternate function"));
+        if pin > 15 || af > 15 {
+            at("pin and alternate function must be 0 - 15");
+        }
+        if !peripherals.contains(peripheral) {
+            at("peripheral not found in the SVD");
+        }
+        if !seen.insert((port.to_string(), pin, af)) {
+            at("duplicate port/pin/af entry");
+        }
+
+        let name = format!("{peripheral}_{signal}");
+        let variant = camel_case(&name);
+        if !signals.iter().any(|(v, _)| *v == variant) {
+            signals.push((variant.clone(), name));
+        }
+        entries.push(format!("    (Port::{port}, {pin}, {af}, Signal::{variant}),"));
+    }
+
+    let mut out = String::new();
+    out.push_str("// Generated by build.rs from data/stm32l496_af.csv. Do not edit.\n\n");
+    out.push_str("/// Peripheral signals that can be routed to a GPIO pin through an alternate function.\n");
+    out.push_str("#[derive(Copy, Clone, Debug, PartialEq)]\n");
+    out.push_str("pub enum Signal {\n");
+    for (variant, name) in &signals {
+        out.push_str(&format!("    /// `{name}`\n    {variant},\n"));
+    }
+    out.push_str("}\n\n");
+    out.push_str("/// `(port, pin, alternate function, signal)` for every routable signal.\n");
+    out.push_str("pub const AF_TABLE: &[(Port, u8, u8, Signal)] = &[\n");
+    for entry in &entries {
+        out.push_str(entry);
+        out.push('\n');
+    }
+    out.push_str("];\n");
+
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("af_table.rs");
+    fs::write(dest, out).expect("Failed to write the AF table");
+}
+
+// Collect the `<name>` of every `<peripheral>` in the SVD
+fn svd_peripherals(svd: &str) -> HashSet<String> {
+    let mut names = HashSet::new();
+    for chunk in svd.split("<peripheral").skip(1) {
+        // Skip `<peripherals>`
+        if chunk.starts_with('s') {
+            continue;
+        }
+        if let Some(start) = chunk.find("<name>") {
+            let rest = &chunk[start + "<name>".len()..];
+            if let Some(end) = rest.find("</name>") {
+                names.insert(rest[..end].trim().to_string());
+            }
+        }
+    }
+    names
+}
+
+// `USART2_RTS_DE` -> `Usart2RtsDe`
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|part| {
+            let lower = part.to_ascii_lowercase();
+            let mut chars = lower.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
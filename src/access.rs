@@ -0,0 +1,188 @@
+//! Register block access.
+//!
+//! Drivers get their register blocks from here instead of dereferencing
+//! `pac::*::ptr()` themselves. On the target the `Hardware` backend hands out the
+//! real peripherals. Host tests use the `sim` backend, which backs every block
+//! with memory so the drivers can be unit-tested without a board:
+//!
+//! ```text
+//! cargo test-host
+//! ```
+
+use core::cell::UnsafeCell;
+use core::ops::Deref;
+use core::ptr;
+
+use crate::gpio::Port;
+use crate::pac::{self, Interrupt, NVIC};
+
+#[cfg(test)]
+pub mod sim;
+
+/// Peripherals the drivers access.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Periph {
+    Gpio(Port),
+    Rcc,
+    Flash,
+    Pwr,
+    Exti,
+    Syscfg,
+    Tim6,
+}
+
+impl Periph {
+    /// Base address of the peripheral's register block.
+    #[cfg_attr(test, allow(dead_code))]
+    pub fn address(&self) -> usize {
+        match self {
+            Self::Gpio(Port::A) => pac::GPIOA::ptr() as usize,
+            Self::Gpio(Port::B) => pac::GPIOB::ptr() as usize,
+            Self::Gpio(Port::C) => pac::GPIOC::ptr() as usize,
+            Self::Gpio(Port::D) => pac::GPIOD::ptr() as usize,
+            Self::Gpio(Port::E) => pac::GPIOE::ptr() as usize,
+            Self::Gpio(Port::F) => pac::GPIOF::ptr() as usize,
+            Self::Gpio(Port::G) => pac::GPIOG::ptr() as usize,
+            Self::Gpio(Port::H) => pac::GPIOH::ptr() as usize,
+            Self::Gpio(Port::I) => pac::GPIOI::ptr() as usize,
+            Self::Rcc => pac::RCC::ptr() as usize,
+            Self::Flash => pac::FLASH::ptr() as usize,
+            Self::Pwr => pac::PWR::ptr() as usize,
+            Self::Exti => pac::EXTI::ptr() as usize,
+            Self::Syscfg => pac::SYSCFG::ptr() as usize,
+            Self::Tim6 => pac::TIM6::ptr() as usize,
+        }
+    }
+}
+
+/// Where register blocks come from.
+pub trait Backend {
+    /// Handle to a register block, dereferencing to the PAC `RegisterBlock`.
+    type Block<T: 'static>: Deref<Target = T>;
+
+    /// Get the register block of `periph`.
+    ///
+    /// # Safety
+    ///
+    /// `T` must be the PAC register block type of `periph`, or a `#[repr(C)]`
+    /// layout of its registers.
+    unsafe fn block<T: 'static>(periph: Periph) -> Self::Block<T>;
+
+    /// Unmask an interrupt in the NVIC.
+    ///
+    /// # Safety
+    ///
+    /// This can break mask-based critical sections.
+    unsafe fn unmask(interrupt: Interrupt);
+
+    /// Mask an interrupt in the NVIC.
+    fn mask(interrupt: Interrupt);
+}
+
+/// The real peripherals.
+#[cfg_attr(test, allow(dead_code))]
+pub struct Hardware;
+
+impl Backend for Hardware {
+    type Block<T: 'static> = &'static T;
+
+    unsafe fn block<T: 'static>(periph: Periph) -> &'static T {
+        unsafe { &*(periph.address() as *const T) }
+    }
+
+    unsafe fn unmask(interrupt: Interrupt) {
+        unsafe { NVIC::unmask(interrupt) }
+    }
+
+    fn mask(interrupt: Interrupt) {
+        NVIC::mask(interrupt);
+    }
+}
+
+#[cfg(not(test))]
+pub type Active = Hardware;
+#[cfg(test)]
+pub type Active = sim::Simulated;
+
+pub type Block<T> = <Active as Backend>::Block<T>;
+
+pub fn gpio(port: Port) -> Block<pac::gpioa::RegisterBlock> {
+    // Note that all ports are accessed as GPIOA, since not all ports deref to
+    // GPIOA in the PAC.
+    unsafe { Active::block(Periph::Gpio(port)) }
+}
+
+pub fn rcc() -> Block<pac::rcc::RegisterBlock> {
+    unsafe { Active::block(Periph::Rcc) }
+}
+
+/// RCC registers missing from the PAC, accessed through the RCC block.
+pub fn rcc_ext() -> Block<RccExt> {
+    unsafe { Active::block(Periph::Rcc) }
+}
+
+pub fn flash() -> Block<pac::flash::RegisterBlock> {
+    unsafe { Active::block(Periph::Flash) }
+}
+
+pub fn pwr() -> Block<pac::pwr::RegisterBlock> {
+    unsafe { Active::block(Periph::Pwr) }
+}
+
+pub fn exti() -> Block<pac::exti::RegisterBlock> {
+    unsafe { Active::block(Periph::Exti) }
+}
+
+pub fn syscfg() -> Block<pac::syscfg::RegisterBlock> {
+    unsafe { Active::block(Periph::Syscfg) }
+}
+
+pub fn tim6() -> Block<pac::tim6::RegisterBlock> {
+    unsafe { Active::block(Periph::Tim6) }
+}
+
+/// The RCC registers the PAC doesn't describe, laid out from the RCC base.
+#[repr(C)]
+pub struct RccExt {
+    _reserved: [u32; 0x9C / 4],
+    ccipr2: Raw,
+}
+
+impl RccExt {
+    /// `RCC_CCIPR2`. The L496 only has `I2C4SEL` (bits 1:0) in it.
+    pub fn ccipr2(&self) -> &Raw {
+        &self.ccipr2
+    }
+}
+
+/// A register without a PAC description, accessed as a whole word.
+#[repr(transparent)]
+pub struct Raw(UnsafeCell<u32>);
+
+impl Raw {
+    pub fn read(&self) -> u32 {
+        unsafe { ptr::read_volatile(self.0.get()) }
+    }
+
+    pub fn write(&self, value: u32) {
+        unsafe { ptr::write_volatile(self.0.get(), value) }
+    }
+
+    pub fn modify<F: FnOnce(u32) -> u32>(&self, f: F) {
+        self.write(f(self.read()));
+    }
+}
+
+/// Unmask an interrupt in the NVIC.
+///
+/// # Safety
+///
+/// This can break mask-based critical sections.
+pub unsafe fn unmask(interrupt: Interrupt) {
+    unsafe { Active::unmask(interrupt) }
+}
+
+/// Mask an interrupt in the NVIC.
+pub fn mask(interrupt: Interrupt) {
+    Active::mask(interrupt);
+}
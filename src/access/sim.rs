@@ -0,0 +1,430 @@
+//! In-memory register simulation for host tests.
+//!
+//! Every peripheral is backed by 1 KiB of memory holding its reset values. Each
+//! time a driver dereferences a block handle, and when the handle is dropped, the
+//! simulation compares memory with its last snapshot: read-only bits are
+//! restored and the remaining changes go in the write log. Then the hardware
+//! scripts run, e.g. to set a ready bit once its enable bit has been written.
+//!
+//! `EXTI_PR1` is write-1-to-clear: a driver write clears the bits it sets. Since
+//! only changes are seen, writing back exactly the value the register holds
+//! doesn't clear anything.
+//!
+//! `reset()` installs scripts for the RCC ready bits, the RCC switch status and
+//! GPIO `BSRR`/`BRR`. Tests can add their own with `script()`, or drop them with
+//! `clear_scripts()` to check what happens when hardware never responds.
+//!
+//! State is per thread, so tests can run in parallel. Call `reset()` at the start
+//! of every test.
+
+// Not every test uses every part of the API
+#![allow(dead_code)]
+
+use std::boxed::Box;
+use std::cell::RefCell;
+use std::vec::Vec;
+
+use core::ops::Deref;
+use core::ptr;
+
+use super::{Backend, Periph};
+use crate::gpio::Port;
+use crate::pac::Interrupt;
+
+// Registers per peripheral
+const WORDS: usize = 256;
+
+const PERIPHS: [Periph; 15] = [
+    Periph::Gpio(Port::A),
+    Periph::Gpio(Port::B),
+    Periph::Gpio(Port::C),
+    Periph::Gpio(Port::D),
+    Periph::Gpio(Port::E),
+    Periph::Gpio(Port::F),
+    Periph::Gpio(Port::G),
+    Periph::Gpio(Port::H),
+    Periph::Gpio(Port::I),
+    Periph::Rcc,
+    Periph::Flash,
+    Periph::Pwr,
+    Periph::Exti,
+    Periph::Syscfg,
+    Periph::Tim6,
+];
+
+// Register offsets used by the default scripts and reset values
+pub const GPIO_MODER: usize = 0x00;
+pub const GPIO_OTYPER: usize = 0x04;
+pub const GPIO_OSPEEDR: usize = 0x08;
+pub const GPIO_PUPDR: usize = 0x0C;
+pub const GPIO_IDR: usize = 0x10;
+pub const GPIO_ODR: usize = 0x14;
+pub const GPIO_BSRR: usize = 0x18;
+pub const GPIO_LCKR: usize = 0x1C;
+pub const GPIO_AFRL: usize = 0x20;
+pub const GPIO_AFRH: usize = 0x24;
+pub const GPIO_BRR: usize = 0x28;
+pub const GPIO_ASCR: usize = 0x2C;
+
+pub const RCC_CR: usize = 0x00;
+pub const RCC_CFGR: usize = 0x08;
+pub const RCC_PLLCFGR: usize = 0x0C;
+pub const RCC_PLLSAI1CFGR: usize = 0x10;
+pub const RCC_PLLSAI2CFGR: usize = 0x14;
+pub const RCC_AHB2RSTR: usize = 0x2C;
+pub const RCC_APB1RSTR1: usize = 0x38;
+pub const RCC_APB1RSTR2: usize = 0x3C;
+pub const RCC_AHB2ENR: usize = 0x4C;
+pub const RCC_APB1ENR1: usize = 0x58;
+pub const RCC_APB1ENR2: usize = 0x5C;
+pub const RCC_APB2ENR: usize = 0x60;
+pub const RCC_CCIPR: usize = 0x88;
+pub const RCC_BDCR: usize = 0x90;
+pub const RCC_CSR: usize = 0x94;
+pub const RCC_CRRCR: usize = 0x98;
+pub const RCC_CCIPR2: usize = 0x9C;
+
+pub const FLASH_ACR: usize = 0x00;
+
+pub const PWR_CR1: usize = 0x00;
+pub const PWR_CR2: usize = 0x04;
+pub const PWR_SR2: usize = 0x14;
+
+pub const EXTI_IMR1: usize = 0x00;
+pub const EXTI_RTSR1: usize = 0x08;
+pub const EXTI_FTSR1: usize = 0x0C;
+pub const EXTI_PR1: usize = 0x14;
+
+pub const SYSCFG_EXTICR1: usize = 0x08;
+
+pub const TIM_CR1: usize = 0x00;
+pub const TIM_DIER: usize = 0x0C;
+pub const TIM_PSC: usize = 0x28;
+pub const TIM_ARR: usize = 0x2C;
+
+/// One register change made by a driver.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Write {
+    pub periph: Periph,
+    pub offset: usize,
+    pub old: u32,
+    pub new: u32,
+}
+
+/// Simulated register state, as seen by the hardware scripts.
+pub struct Registers {
+    mem: *mut u32,
+    shadow: Vec<u32>,
+    read_only: Vec<(Periph, usize, u32)>,
+    write_one_to_clear: Vec<(Periph, usize)>,
+    log: Vec<Write>,
+    unmasked: Vec<u16>,
+}
+
+impl Registers {
+    fn index(periph: Periph, offset: usize) -> usize {
+        assert!(offset % 4 == 0 && offset < WORDS * 4, "Bad register offset {offset:#x}.");
+        let block = PERIPHS.iter().position(|p| *p == periph).unwrap();
+        block * WORDS + offset / 4
+    }
+
+    /// Read a register.
+    pub fn read(&self, periph: Periph, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.mem.add(Self::index(periph, offset))) }
+    }
+
+    /// Write a register as the hardware would. Isn't logged and ignores read-only bits.
+    pub fn write(&mut self, periph: Periph, offset: usize, value: u32) {
+        let index = Self::index(periph, offset);
+        unsafe { ptr::write_volatile(self.mem.add(index), value) };
+        self.shadow[index] = value;
+    }
+
+    pub fn set_bits(&mut self, periph: Periph, offset: usize, mask: u32) {
+        let value = self.read(periph, offset) | mask;
+        self.write(periph, offset, value);
+    }
+
+    pub fn clear_bits(&mut self, periph: Periph, offset: usize, mask: u32) {
+        let value = self.read(periph, offset) & !mask;
+        self.write(periph, offset, value);
+    }
+
+    // Pick up the driver writes since the last sync
+    fn collect(&mut self) {
+        for (block, periph) in PERIPHS.iter().enumerate() {
+            for word in 0..WORDS {
+                let index = block * WORDS + word;
+                let value = unsafe { ptr::read_volatile(self.mem.add(index)) };
+                let old = self.shadow[index];
+                if value == old {
+                    continue;
+                }
+
+                let offset = word * 4;
+                let read_only = self
+                    .read_only
+                    .iter()
+                    .filter(|(p, o, _)| p == periph && *o == offset)
+                    .fold(0, |mask, (_, _, m)| mask | m);
+                let mut new = (value & !read_only) | (old & read_only);
+                if self.write_one_to_clear.contains(&(*periph, offset)) {
+                    new = old & !value;
+                }
+                if new != value {
+                    unsafe { ptr::write_volatile(self.mem.add(index), new) };
+                }
+                if new != old {
+                    self.log.push(Write { periph: *periph, offset, old, new });
+                }
+                self.shadow[index] = new;
+            }
+        }
+    }
+}
+
+type Script = Box<dyn FnMut(&mut Registers)>;
+
+struct State {
+    regs: Registers,
+    scripts: Vec<Script>,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State {
+        regs: Registers {
+            mem: Box::leak(vec![0u32; PERIPHS.len() * WORDS].into_boxed_slice()).as_mut_ptr(),
+            shadow: vec![0; PERIPHS.len() * WORDS],
+            read_only: Vec::new(),
+            write_one_to_clear: Vec::new(),
+            log: Vec::new(),
+            unmasked: Vec::new(),
+        },
+        scripts: Vec::new(),
+    });
+}
+
+fn sync() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let State { regs, scripts } = &mut *state;
+        regs.collect();
+        for script in scripts.iter_mut() {
+            script(regs);
+        }
+    });
+}
+
+/// Put every register back to its reset value, clear the log and install the
+/// default read-only bits and scripts.
+pub fn reset() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let regs = &mut state.regs;
+
+        for periph in PERIPHS {
+            for word in 0..WORDS {
+                regs.write(periph, word * 4, 0);
+            }
+        }
+        regs.log.clear();
+        regs.unmasked.clear();
+        regs.read_only.clear();
+        regs.write_one_to_clear.clear();
+        regs.write_one_to_clear.push((Periph::Exti, EXTI_PR1));
+
+        regs.write(Periph::Gpio(Port::A), GPIO_MODER, 0xABFF_FFFF);
+        regs.write(Periph::Gpio(Port::A), GPIO_OSPEEDR, 0x0C00_0000);
+        regs.write(Periph::Gpio(Port::A), GPIO_PUPDR, 0x6400_0000);
+        regs.write(Periph::Gpio(Port::B), GPIO_MODER, 0xFFFF_FEBF);
+        regs.write(Periph::Gpio(Port::B), GPIO_PUPDR, 0x0000_0100);
+        for port in [Port::C, Port::D, Port::E, Port::F, Port::G, Port::H, Port::I] {
+            regs.write(Periph::Gpio(port), GPIO_MODER, 0xFFFF_FFFF);
+        }
+        // MSI on and ready at 4 MHz
+        regs.write(Periph::Rcc, RCC_CR, 0x0000_0063);
+        regs.write(Periph::Rcc, RCC_PLLCFGR, 0x0000_1000);
+        regs.write(Periph::Flash, FLASH_ACR, 0x0000_0600);
+        // Range 1
+        regs.write(Periph::Pwr, PWR_CR1, 0x0000_0200);
+
+        for port in [Port::A, Port::B, Port::C, Port::D, Port::E, Port::F, Port::G, Port::H, Port::I] {
+            regs.read_only.push((Periph::Gpio(port), GPIO_IDR, 0xFFFF_FFFF));
+        }
+        // MSIRDY, HSIRDY, HSERDY, PLLRDY, PLLSAI1RDY, PLLSAI2RDY
+        regs.read_only.push((Periph::Rcc, RCC_CR, 0x2A02_0402));
+        // LSERDY, LSECSSD
+        regs.read_only.push((Periph::Rcc, RCC_BDCR, 0b100_0010));
+        // LSIRDY
+        regs.read_only.push((Periph::Rcc, RCC_CSR, 0b10));
+        // HSI48RDY
+        regs.read_only.push((Periph::Rcc, RCC_CRRCR, 0b10));
+        // SWS
+        regs.read_only.push((Periph::Rcc, RCC_CFGR, 0b1100));
+        regs.read_only.push((Periph::Pwr, PWR_SR2, 0xFFFF_FFFF));
+
+        state.scripts.clear();
+        state.scripts.push(Box::new(rcc_ready));
+        state.scripts.push(Box::new(rcc_switch));
+        state.scripts.push(Box::new(gpio_bsrr));
+    });
+}
+
+// Oscillator ready bits follow their enable bits
+fn rcc_ready(regs: &mut Registers) {
+    // (ON, RDY) for MSI, HSI16, HSE, PLL, PLLSAI1, PLLSAI2
+    follow(regs, RCC_CR, &[(0, 1), (8, 10), (16, 17), (24, 25), (26, 27), (28, 29)]);
+    // LSE
+    follow(regs, RCC_BDCR, &[(0, 1)]);
+    // LSI
+    follow(regs, RCC_CSR, &[(0, 1)]);
+    // HSI48
+    follow(regs, RCC_CRRCR, &[(0, 1)]);
+}
+
+// Set each RDY bit of an RCC register to its ON bit
+fn follow(regs: &mut Registers, offset: usize, bits: &[(u32, u32)]) {
+    let value = regs.read(Periph::Rcc, offset);
+    let ready = bits
+        .iter()
+        .filter(|(on, _)| value & (1 << on) != 0)
+        .fold(0, |ready, (_, rdy)| ready | (1 << rdy));
+    let mask = bits.iter().fold(0, |mask, (_, rdy)| mask | (1 << rdy));
+    if value & mask != ready {
+        regs.write(Periph::Rcc, offset, (value & !mask) | ready);
+    }
+}
+
+// The system clock switch status follows the switch
+fn rcc_switch(regs: &mut Registers) {
+    let cfgr = regs.read(Periph::Rcc, RCC_CFGR);
+    let sws = (cfgr & 0b11) << 2;
+    if cfgr & 0b1100 != sws {
+        regs.write(Periph::Rcc, RCC_CFGR, (cfgr & !0b1100) | sws);
+    }
+}
+
+// BSRR and BRR drive ODR and read back as 0
+fn gpio_bsrr(regs: &mut Registers) {
+    for port in [Port::A, Port::B, Port::C, Port::D, Port::E, Port::F, Port::G, Port::H, Port::I] {
+        let periph = Periph::Gpio(port);
+        let bsrr = regs.read(periph, GPIO_BSRR);
+        let brr = regs.read(periph, GPIO_BRR);
+        if bsrr == 0 && brr == 0 {
+            continue;
+        }
+
+        let set = bsrr & 0xFFFF;
+        let reset = (bsrr >> 16) | (brr & 0xFFFF);
+        // Set wins over reset
+        let odr = (regs.read(periph, GPIO_ODR) & !reset) | set;
+        regs.write(periph, GPIO_ODR, odr);
+        regs.write(periph, GPIO_BSRR, 0);
+        regs.write(periph, GPIO_BRR, 0);
+    }
+}
+
+/// Read a register.
+pub fn read(periph: Periph, offset: usize) -> u32 {
+    sync();
+    STATE.with(|state| state.borrow().regs.read(periph, offset))
+}
+
+/// Write a register as the hardware would, e.g. to drive an input. Isn't logged.
+pub fn write(periph: Periph, offset: usize, value: u32) {
+    sync();
+    STATE.with(|state| state.borrow_mut().regs.write(periph, offset, value));
+}
+
+/// Every driver write since the last `reset()` or `clear_writes()`, in order.
+pub fn writes() -> Vec<Write> {
+    sync();
+    STATE.with(|state| state.borrow().regs.log.clone())
+}
+
+/// Driver writes to one register, in order.
+pub fn writes_to(periph: Periph, offset: usize) -> Vec<Write> {
+    writes()
+        .into_iter()
+        .filter(|write| write.periph == periph && write.offset == offset)
+        .collect()
+}
+
+pub fn clear_writes() {
+    sync();
+    STATE.with(|state| state.borrow_mut().regs.log.clear());
+}
+
+/// Make bits of a register read-only to the drivers.
+pub fn read_only(periph: Periph, offset: usize, mask: u32) {
+    STATE.with(|state| state.borrow_mut().regs.read_only.push((periph, offset, mask)));
+}
+
+/// Add a hardware script, run after every driver register access.
+pub fn script<F>(f: F)
+where
+    F: FnMut(&mut Registers) + 'static,
+{
+    STATE.with(|state| state.borrow_mut().scripts.push(Box::new(f)));
+}
+
+/// Remove every hardware script, including the defaults.
+pub fn clear_scripts() {
+    STATE.with(|state| state.borrow_mut().scripts.clear());
+}
+
+/// Whether an interrupt is unmasked in the NVIC.
+pub fn is_unmasked(interrupt: Interrupt) -> bool {
+    STATE.with(|state| state.borrow().regs.unmasked.contains(&(interrupt as u16)))
+}
+
+/// The simulated peripherals.
+pub struct Simulated;
+
+/// Handle to a simulated register block. Syncs the simulation on every access.
+pub struct Block<T: 'static> {
+    block: &'static T,
+}
+
+impl<T> Deref for Block<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        sync();
+        self.block
+    }
+}
+
+impl<T> Drop for Block<T> {
+    fn drop(&mut self) {
+        sync();
+    }
+}
+
+impl Backend for Simulated {
+    type Block<T: 'static> = Block<T>;
+
+    unsafe fn block<T: 'static>(periph: Periph) -> Block<T> {
+        assert!(size_of::<T>() <= WORDS * 4);
+        let mem = STATE.with(|state| {
+            let state = state.borrow();
+            unsafe { state.regs.mem.add(Registers::index(periph, 0)) }
+        });
+        Block {
+            block: unsafe { &*(mem as *const T) },
+        }
+    }
+
+    unsafe fn unmask(interrupt: Interrupt) {
+        STATE.with(|state| {
+            let unmasked = &mut state.borrow_mut().regs.unmasked;
+            if !unmasked.contains(&(interrupt as u16)) {
+                unmasked.push(interrupt as u16);
+            }
+        });
+    }
+
+    fn mask(interrupt: Interrupt) {
+        STATE.with(|state| state.borrow_mut().regs.unmasked.retain(|i| *i != interrupt as u16));
+    }
+}
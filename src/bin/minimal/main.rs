@@ -6,7 +6,8 @@ use cortex_m_rt::entry;
 use cortex_m::interrupt::Mutex;
 use panic_halt as _;
 //use crate::pac as _;
-use stm32l4_hal::gpio::{Pin, PinMode, Port};
+use stm32l4_hal::gpio::GpioExt;
+use stm32l4_hal::pac;
 use stm32l4_hal::rcc::ClockManager;
 use stm32l4_hal::timer::{Timer};
 //use stm32l4_hal::rcc::{VRange1, SourceMSI, PLLDisabled};
@@ -15,13 +16,15 @@ use stm32l4_hal::rcc::MSIRange;
 #[entry]
 fn main() -> ! {
     //let _cp = cortex_m::Peripherals::take().unwrap();
-    //let dp = pac::Peripherals::take().unwrap();
+    let dp = pac::Peripherals::take().unwrap();
     let mut cm= ClockManager::new();
-    cm.update_msi_range(MSIRange::Range11);
+    cm.update_msi_range(MSIRange::Range11).unwrap();
+    let clocks = cm.freeze();
 
-    let mut led = Pin::new(Port::B, 7, PinMode::Output);
+    let gpiob = dp.GPIOB.split();
+    let mut led = gpiob.pb7.into_push_pull_output();
 
-    let tim6 = Timer::new();
+    let tim6 = Timer::new(&clocks);
     tim6.start();
 
     loop {
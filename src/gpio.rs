@@ -1,13 +1,48 @@
 use core::convert::Infallible;
 
-use embedded_hal::digital::{ErrorType, InputPin, OutputPin, StatefulOutputPin};
+use embedded_hal::digital::{ErrorKind, ErrorType, InputPin, OutputPin, StatefulOutputPin};
 use stm32l4::stm32l4x6::gpioa::ospeedr;
 
-use crate::pac::{self, EXTI, RCC};
-//use crate::util::rcc_en_reset;
+use crate::access::{self, Block};
+use crate::pac::{self, Interrupt};
+use crate::rcc::{Enable, Reset};
 
 use paste::paste;
 
+pub mod af;
+pub mod analog;
+pub mod exti;
+pub mod group;
+pub mod typed;
+
+pub use af::Signal;
+pub use analog::{AdcInputPin, AdcInstance, AdcPin};
+pub use group::PinGroup;
+pub use typed::GpioExt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// Errors returned by GPIO configuration.
+pub enum Error {
+    /// Alternate function number is outside AF0 - AF15.
+    InvalidAltFn(u8),
+    /// The pin can't carry the requested peripheral signal.
+    InvalidAltMapping(Signal),
+    /// Pins passed to a port-wide operation are on different ports.
+    PortMismatch,
+    /// The `LCKR` key sequence didn't lock the port.
+    LockFailed,
+    /// The pin isn't an ADC input, so it has no analog switch.
+    NoAdcChannel,
+    /// Another pin is already being waited on through this EXTI line.
+    ExtiLineInUse(u8),
+}
+
+impl embedded_hal::digital::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
 #[derive(Copy, Clone)]
 #[repr(u8)]
 pub enum PinMode {
@@ -75,7 +110,7 @@ pub enum ResetState {
     Reset = 1,
 }
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Port {
     A,
     B,
@@ -89,7 +124,7 @@ pub enum Port {
 }
 
 impl Port {
-    fn cr_val(&self) -> u8 {
+    pub(crate) fn cr_val(&self) -> u8 {
         match self {
             Self::A => 0,
             Self::B => 1,
@@ -119,6 +154,8 @@ pub enum Edge {
 macro_rules! set_field {
     ($regs:expr, $pin:expr, $reg:ident, $field:ident, $bit:ident, $val:expr, [$($num:expr),+]) => {
         paste! {
+            // Only some field writers are unsafe
+            #[allow(unused_unsafe)]
             unsafe {
                 match $pin {
                     $(
@@ -149,116 +186,87 @@ macro_rules! set_state {
 macro_rules! get_input_data {
     ($regs: expr, $pin:expr, [$($num:expr),+]) => {
         paste! {
-            unsafe {
-                match $pin {
-                    $(
-                        $num => (*$regs).idr().read().[<idr $num>]().bit_is_set(),
-                    )+
-                    _ => panic!("GPIO pins must be 0 - 15."),
-                }
+            match $pin {
+                $(
+                    $num => (*$regs).idr().read().[<idr $num>]().bit_is_set(),
+                )+
+                _ => panic!("GPIO pins must be 0 - 15."),
             }
         }
     }
 }
 
-#[derive(Clone)]
+macro_rules! get_output_data {
+    ($regs: expr, $pin:expr, [$($num:expr),+]) => {
+        paste! {
+            match $pin {
+                $(
+                    $num => (*$regs).odr().read().[<odr $num>]().bit_is_set(),
+                )+
+                _ => panic!("GPIO pins must be 0 - 15."),
+            }
+        }
+    }
+}
+
+/// A dynamic pin. It isn't `Clone`, so a pin moved into a `LockedPin` or an
+/// async wait can't be reconfigured behind its back through a copy.
+#[derive(Debug)]
 pub struct Pin {
     pub port: Port,
     pub pin: u8,
 }
 
-/*macro_rules! gpio_rcc_en {
-    ($port:expr, $rcc:expr) => {
-        if $rcc.ahb2enr().read().$port().bit_is_clear() {
-            //$rcc.ahb2enr().write(|w| w.$port().set_bit());
-        }
-    };
-}*/
-
 impl Pin {
+    /// Create a dynamic pin and enable its port clock. Nothing stops the same line
+    /// being created twice; prefer `GpioExt::split`, which hands out owned pins.
+    ///
+    /// # Panics
+    ///
+    /// If `pin` is above 15, or `mode` is `PinMode::Alt` with a function above
+    /// 15. Use `try_new` to get an error for the latter.
     pub fn new(port: Port, pin: u8, mode: PinMode) -> Self {
-        assert!(pin <= 15, "Pin must be 0 - 15.");
+        Self::try_new(port, pin, mode).expect("Alternate function must be 0 - 15.")
+    }
 
-        // Sets the clock
-        let rcc = unsafe { &(*RCC::ptr()) };
-        match port {
-            Port::A => {
-                // Set the AHB2ENR GPIOA Enable
-                //gpio_rcc_en!(gpioaen, rcc);
-                if rcc.ahb2enr().read().gpioaen().bit_is_clear() {
-                    rcc.ahb2enr().write(|w| w.gpioaen().set_bit());
-                }
-            }
-            Port::B => {
-                if rcc.ahb2enr().read().gpioben().bit_is_clear() {
-                    rcc.ahb2enr().write(|w| w.gpioben().set_bit());
-                }
-            }
-            Port::C => {
-                if rcc.ahb2enr().read().gpiocen().bit_is_clear() {
-                    rcc.ahb2enr().write(|w| w.gpiocen().set_bit());
-                }
-            }
-            Port::D => {
-                if rcc.ahb2enr().read().gpioden().bit_is_clear() {
-                    rcc.ahb2enr().write(|w| w.gpioden().set_bit());
-                }
-            }
-            Port::E => {
-                if rcc.ahb2enr().read().gpioeen().bit_is_clear() {
-                    rcc.ahb2enr().write(|w| w.gpioeen().set_bit());
-                }
-            }
-            Port::F => {
-                if rcc.ahb2enr().read().gpiofen().bit_is_clear() {
-                    rcc.ahb2enr().write(|w| w.gpiofen().set_bit());
-                }
-            }
-            Port::G => {
-                // Port G needs to enable the power bit
-                if rcc.ahb2enr().read().gpiogen().bit_is_clear() {
-                    rcc.ahb2enr().write(|w| w.gpiogen().set_bit());
-
-                    // Set pwr bit and iobank2
-                    let pwr = unsafe { &(*pac::PWR::ptr()) };
-                    rcc.apb1enr1().modify(|_, w| w.pwren().set_bit());
-                    pwr.cr2().modify(|_, w| w.iosv().set_bit());
-                }
-            }
-            Port::H => {
-                if rcc.ahb2enr().read().gpiohen().bit_is_clear() {
-                    rcc.ahb2enr().write(|w| w.gpiohen().set_bit());
-                }
-            }
-            Port::I => {
-                if rcc.ahb2enr().read().gpioien().bit_is_clear() {
-                    //rcc.ahb2enr().write(|w| w.gpiohen().set_bit());
-                    rcc.ahb2enr().modify(|_,w| w.gpioien().set_bit());
-                    rcc.ahb2rstr().modify(|_,w| w.gpioirst().set_bit());
-                    rcc.ahb2rstr().modify(|_,w| w.gpioirst().clear_bit());
-                }
-            }
+    /// Like `new`, but an alternate function above 15 fails with `InvalidAltFn`
+    /// before the port is touched.
+    pub fn try_new(port: Port, pin: u8, mode: PinMode) -> Result<Self, Error> {
+        assert!(pin <= 15, "Pin must be 0 - 15.");
+        if let PinMode::Alt(alt @ 16..) = mode {
+            return Err(Error::InvalidAltFn(alt));
         }
 
+        enable_port_clock(port);
+
         let mut result = Self { port, pin };
-        result.mode(mode);
+        result.mode(mode)?;
+        Ok(result)
+    }
 
-        result
+    /// Create a pin in alternate function mode routed to `signal`, checking the
+    /// pin against the STM32L496 alternate function table first.
+    pub fn new_alt(port: Port, pin: u8, signal: Signal) -> Result<Self, Error> {
+        match af::lookup(port, pin, signal) {
+            Some(alt) => Self::try_new(port, pin, PinMode::Alt(alt)),
+            None => Err(Error::InvalidAltMapping(signal)),
+        }
     }
 
-    
+    /// Route a peripheral signal to this pin. Sets the alternate function and mode.
+    pub fn route(&mut self, signal: Signal) -> Result<(), Error> {
+        let alt = af::lookup(self.port, self.pin, signal).ok_or(Error::InvalidAltMapping(signal))?;
+        self.mode(PinMode::Alt(alt))
+    }
+
+    /// Set the pin mode. Sets the `MODER` register, and `AFRL`/`AFRH` for `PinMode::Alt`.
+    pub fn mode(&mut self, value: PinMode) -> Result<(), Error> {
+        // Select the alternate function before switching the mode so the pin
+        // never drives a stale peripheral signal
+        if let PinMode::Alt(alt) = value {
+            self.alt_fn(alt)?;
+        }
 
-    pub fn mode(&mut self, value: PinMode) {
-        let pinnum = self.pin;
-        /*match self.port {
-            Port::A => {
-                paste! {
-                    let gpioa = unsafe { &(*crate::pac::GPIOA::ptr()) };
-                    gpioa.moder().write(|w| unsafe {w.[<moder pinnum>]().bits(value.val()) });
-                }
-            }
-            _ => panic!("TODO")
-        }*/
         set_field!(
             self.regs(),
             self.pin,
@@ -269,9 +277,39 @@ impl Pin {
             [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
         );
 
-        //if let PinMode::Alt(alt) = value {
-        //    self.alt_fn(alt);
-        //}
+        Ok(())
+    }
+
+    /// Select the alternate function (AF0 - AF15) without changing the pin mode.
+    /// Sets `AFRL` for pins 0 - 7 and `AFRH` for pins 8 - 15.
+    pub fn alt_fn(&mut self, value: u8) -> Result<(), Error> {
+        if value > 15 {
+            return Err(Error::InvalidAltFn(value));
+        }
+
+        if self.pin < 8 {
+            set_field!(
+                self.regs(),
+                self.pin,
+                afrl,
+                afrl,
+                bits,
+                value,
+                [0, 1, 2, 3, 4, 5, 6, 7]
+            );
+        } else {
+            set_field!(
+                self.regs(),
+                self.pin,
+                afrh,
+                afrh,
+                bits,
+                value,
+                [8, 9, 10, 11, 12, 13, 14, 15]
+            );
+        }
+
+        Ok(())
     }
 
     // Output Type, Sets GPIOx_OTYPER
@@ -316,17 +354,20 @@ impl Pin {
         );
     }
 
-    /// Lock or unlock a port configuration. Sets the `LCKR` register.
-    pub fn cfg_lock(&mut self, value: CfgLock) {
-        set_field!(
-            self.regs(),
-            self.pin,
-            lckr,
-            lck,
-            bit,
-            value as u8 != 0,
-            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
-        );
+    /// Lock the pin configuration until the next reset. Runs the `LCKR` key
+    /// sequence and returns a `LockedPin`, which can no longer be reconfigured.
+    /// On failure the pin is handed back with the error.
+    pub fn lock(self) -> Result<LockedPin, (Error, Pin)> {
+        match lock_pins([self]) {
+            Ok([locked]) => Ok(locked),
+            Err((error, [pin])) => Err((error, pin)),
+        }
+    }
+
+    /// Read the pin's lock bit in `LCKR`.
+    pub fn lock_state(&self) -> CfgLock {
+        let lckr = self.regs().lckr().read().bits();
+        if lckr & (1 << self.pin) != 0 { CfgLock::Locked } else { CfgLock::NotLocked }
     }
 
     pub fn get_state(&mut self) -> PinState {
@@ -372,32 +413,256 @@ impl Pin {
         self.set_state(PinState::Low);
     }
 
-    /// Toggle output voltage between low and high. Sets the `BSRR` register. Atomic.
+    /// Check the output latch. Reads `ODR`, so this is the driven state rather than
+    /// the pin level.
+    pub fn is_set_high(&self) -> bool {
+        get_output_data!(
+            self.regs(),
+            self.pin,
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]
+        )
+    }
+
+    pub fn is_set_low(&self) -> bool {
+        !self.is_set_high()
+    }
+
+    /// Toggle output voltage between low and high. Reads `ODR` and writes `BSRR`
+    /// inside a critical section, so an interrupt can't change the pin in between.
     pub fn toggle(&mut self) {
-        // if self.is_high() {
-        if Pin::is_high(self) {
-            Pin::set_low(self);
-            // self.set_low();
-        } else {
-            // self.set_high();
-            Pin::set_high(self);
+        critical_section::with(|_| {
+            if Pin::is_set_high(self) {
+                Pin::set_low(self);
+            } else {
+                Pin::set_high(self);
+            }
+        });
+    }
+
+    /// Enable the EXTI interrupt for this pin on the given edge. Routes the EXTI
+    /// line to this port (`SYSCFG_EXTICRx`), sets the trigger edge (`EXTI_RTSR1`,
+    /// `EXTI_FTSR1`), unmasks the line (`EXTI_IMR1`) and unmasks the NVIC vector.
+    pub fn enable_interrupt(&mut self, edge: Edge) {
+        let syscfg = access::syscfg();
+        let exti = access::exti();
+
+        // SYSCFG clock is needed to select the EXTI port
+        pac::SYSCFG::enable();
+
+        // Each EXTICR register holds 4 lines of 4 bits each
+        let shift = (self.pin % 4) * 4;
+        let port = self.port.cr_val() as u32;
+        let cr_modify = |bits: u32| (bits & !(0xF << shift)) | (port << shift);
+        unsafe {
+            match self.pin / 4 {
+                0 => syscfg.exticr1().modify(|r, w| w.bits(cr_modify(r.bits()))),
+                1 => syscfg.exticr2().modify(|r, w| w.bits(cr_modify(r.bits()))),
+                2 => syscfg.exticr3().modify(|r, w| w.bits(cr_modify(r.bits()))),
+                3 => syscfg.exticr4().modify(|r, w| w.bits(cr_modify(r.bits()))),
+                _ => panic!("GPIO pins must be 0 - 15."),
+            };
+        }
+
+        let (rising, falling) = match edge {
+            Edge::Rising => (true, false),
+            Edge::Falling => (false, true),
+            Edge::Either => (true, true),
+        };
+        let mask = 1 << self.pin;
+        unsafe {
+            exti.rtsr1().modify(|r, w| w.bits(if rising { r.bits() | mask } else { r.bits() & !mask }));
+            exti.ftsr1().modify(|r, w| w.bits(if falling { r.bits() | mask } else { r.bits() & !mask }));
+
+            // Don't fire straight away on an edge from before the pin was configured
+            exti.pr1().write(|w| w.bits(mask));
+            exti.imr1().modify(|r, w| w.bits(r.bits() | mask));
+
+            access::unmask(self.interrupt());
+        }
+    }
+
+    /// Mask the EXTI line for this pin. The NVIC vector is masked too unless it
+    /// is shared (`EXTI9_5`, `EXTI15_10`) with another line that is still enabled.
+    pub fn disable_interrupt(&mut self) {
+        let exti = access::exti();
+
+        let mask = 1 << self.pin;
+        unsafe {
+            exti.imr1().modify(|r, w| w.bits(r.bits() & !mask));
+            exti.rtsr1().modify(|r, w| w.bits(r.bits() & !mask));
+            exti.ftsr1().modify(|r, w| w.bits(r.bits() & !mask));
+        }
+
+        let vector_lines = match self.pin {
+            5..=9 => 0x03E0,
+            10..=15 => 0xFC00,
+            _ => mask,
+        };
+        if exti.imr1().read().bits() & vector_lines == 0 {
+            access::mask(self.interrupt());
         }
     }
 
-    const fn regs(&self) -> *const pac::gpioa::RegisterBlock {
-        // Note that we use this `const` fn and pointer casting since not all ports actually
-        // deref to GPIOA in PAC.
+    /// Check if the EXTI line for this pin has a pending interrupt. Reads `EXTI_PR1`.
+    pub fn is_interrupt_pending(&self) -> bool {
+        let exti = access::exti();
+        exti.pr1().read().bits() & (1 << self.pin) != 0
+    }
+
+    /// Clear the pending interrupt for this pin. Writes 1 to `EXTI_PR1`.
+    pub fn clear_interrupt(&mut self) {
+        let exti = access::exti();
+        unsafe {
+            exti.pr1().write(|w| w.bits(1 << self.pin));
+        }
+    }
+
+    /// NVIC vector serving this pin's EXTI line.
+    fn interrupt(&self) -> Interrupt {
+        match self.pin {
+            0 => Interrupt::EXTI0,
+            1 => Interrupt::EXTI1,
+            2 => Interrupt::EXTI2,
+            3 => Interrupt::EXTI3,
+            4 => Interrupt::EXTI4,
+            5..=9 => Interrupt::EXTI9_5,
+            10..=15 => Interrupt::EXTI15_10,
+            _ => panic!("GPIO pins must be 0 - 15."),
+        }
+    }
+
+    fn regs(&self) -> Block<pac::gpioa::RegisterBlock> {
         regs(self.port)
     }
+}
+
+/// Lock the configuration of several pins on one port until the next reset.
+///
+/// `LCKR` can only take one key sequence per reset, so all the pins that need
+/// locking on a port should be passed together. If the port is already locked,
+/// this succeeds only if every pin was part of that lock.
+///
+/// On failure the pins are handed back with the error, unchanged.
+pub fn lock_pins<const N: usize>(pins: [Pin; N]) -> Result<[LockedPin; N], (Error, [Pin; N])> {
+    let Some(first) = pins.first() else {
+        return Ok(pins.map(|pin| LockedPin { pin }));
+    };
+    if pins.iter().any(|pin| pin.port != first.port) {
+        return Err((Error::PortMismatch, pins));
+    }
 
-    
+    const LCKK: u32 = 1 << 16;
+    let mask = pins.iter().fold(0u32, |mask, pin| mask | (1 << pin.pin));
+    let regs = first.regs();
+
+    if regs.lckr().read().bits() & LCKK == 0 {
+        // Key sequence: write LCKK=1, LCKK=0, LCKK=1 with the same lock bits, then
+        // read twice. It must not be interrupted.
+        critical_section::with(|_| unsafe {
+            regs.lckr().write(|w| w.bits(LCKK | mask));
+            regs.lckr().write(|w| w.bits(mask));
+            regs.lckr().write(|w| w.bits(LCKK | mask));
+            regs.lckr().read();
+        });
+    }
+
+    let locked = regs.lckr().read().bits();
+    if locked & LCKK == 0 || locked & mask != mask {
+        return Err((Error::LockFailed, pins));
+    }
+
+    Ok(pins.map(|pin| LockedPin { pin }))
 }
 
+/// A pin whose configuration is locked until the next reset. Only the pin level
+/// can be read and driven; `mode`, `pull`, `output_type` etc. aren't available.
+pub struct LockedPin {
+    pin: Pin,
+}
 
-impl ErrorType for Pin {
+impl LockedPin {
+    pub fn port(&self) -> Port {
+        self.pin.port
+    }
+
+    pub fn pin(&self) -> u8 {
+        self.pin.pin
+    }
+
+    pub fn is_high(&self) -> bool {
+        self.pin.is_high()
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.pin.is_low()
+    }
+
+    pub fn set_high(&mut self) {
+        self.pin.set_high();
+    }
+
+    pub fn set_low(&mut self) {
+        self.pin.set_low();
+    }
+
+    pub fn is_set_high(&self) -> bool {
+        self.pin.is_set_high()
+    }
+
+    pub fn is_set_low(&self) -> bool {
+        self.pin.is_set_low()
+    }
+
+    pub fn toggle(&mut self) {
+        self.pin.toggle();
+    }
+}
+
+impl ErrorType for LockedPin {
     type Error = Infallible;
 }
 
+impl InputPin for LockedPin {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(LockedPin::is_high(self))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(LockedPin::is_low(self))
+    }
+}
+
+impl OutputPin for LockedPin {
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        LockedPin::set_high(self);
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        LockedPin::set_low(self);
+        Ok(())
+    }
+}
+
+impl StatefulOutputPin for LockedPin {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(LockedPin::is_set_high(self))
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(LockedPin::is_set_low(self))
+    }
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        LockedPin::toggle(self);
+        Ok(())
+    }
+}
+
+impl ErrorType for Pin {
+    type Error = Error;
+}
+
 impl InputPin for Pin {
     fn is_high(&mut self) -> Result<bool, Self::Error> {
         Ok(Pin::is_high(self))
@@ -408,16 +673,240 @@ impl InputPin for Pin {
     }
 }
 
-const fn regs(port: Port) -> *const pac::gpioa::RegisterBlock {
+impl OutputPin for Pin {
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Pin::set_high(self);
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Pin::set_low(self);
+        Ok(())
+    }
+}
+
+impl StatefulOutputPin for Pin {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_set_high(self))
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_set_low(self))
+    }
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        Pin::toggle(self);
+        Ok(())
+    }
+}
+
+/// Enable the port clock in `AHB2ENR`.
+fn enable_port_clock(port: Port) {
+    let started = match port {
+        Port::A => enable::<pac::GPIOA>(),
+        Port::B => enable::<pac::GPIOB>(),
+        Port::C => enable::<pac::GPIOC>(),
+        Port::D => enable::<pac::GPIOD>(),
+        Port::E => enable::<pac::GPIOE>(),
+        Port::F => enable::<pac::GPIOF>(),
+        Port::G => enable::<pac::GPIOG>(),
+        Port::H => enable::<pac::GPIOH>(),
+        Port::I => enable::<pac::GPIOI>(),
+    };
+    if !started {
+        return;
+    }
+
     match port {
-        Port::A => crate::pac::GPIOA::ptr(),
-        Port::B => crate::pac::GPIOB::ptr() as _,
-        Port::C => crate::pac::GPIOC::ptr() as _,
-        Port::D => crate::pac::GPIOD::ptr() as _,
-        Port::E => crate::pac::GPIOE::ptr() as _,
-        Port::F => crate::pac::GPIOF::ptr() as _,
-        Port::G => crate::pac::GPIOG::ptr() as _,
-        Port::H => crate::pac::GPIOH::ptr() as _,
-        Port::I => crate::pac::GPIOI::ptr() as _,
-    }
-}
\ No newline at end of file
+        Port::G => {
+            // Port G needs to enable the power bit and iobank2
+            if !pac::PWR::is_enabled() {
+                pac::PWR::enable();
+            }
+            let pwr = access::pwr();
+            pwr.cr2().modify(|_, w| w.iosv().set_bit());
+        }
+        Port::I => pac::GPIOI::reset(),
+        _ => {}
+    }
+}
+
+// Enable a port clock if it is off. Returns whether it was.
+fn enable<GPIO: Enable>() -> bool {
+    let off = !GPIO::is_enabled();
+    if off {
+        GPIO::enable();
+    }
+    off
+}
+
+fn regs(port: Port) -> Block<pac::gpioa::RegisterBlock> {
+    access::gpio(port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::sim::{self, *};
+    use crate::access::Periph;
+
+    #[test]
+    fn mode_writes_moder() {
+        sim::reset();
+        Pin::new(Port::B, 7, PinMode::Output);
+
+        let moder = sim::read(Periph::Gpio(Port::B), GPIO_MODER);
+        assert_eq!((moder >> 14) & 0b11, 0b01);
+        // Other pins keep their reset mode
+        assert_eq!(moder & !(0b11 << 14), 0xFFFF_FEBF & !(0b11 << 14));
+        assert_ne!(sim::read(Periph::Rcc, RCC_AHB2ENR) & (1 << 1), 0);
+    }
+
+    #[test]
+    fn alt_fn_is_set_before_mode() {
+        sim::reset();
+        Pin::new(Port::A, 9, PinMode::Alt(7));
+
+        assert_eq!((sim::read(Periph::Gpio(Port::A), GPIO_AFRH) >> 4) & 0xF, 7);
+        assert_eq!((sim::read(Periph::Gpio(Port::A), GPIO_MODER) >> 18) & 0b11, 0b10);
+
+        let order: Vec<usize> = sim::writes()
+            .iter()
+            .filter(|w| w.periph == Periph::Gpio(Port::A))
+            .map(|w| w.offset)
+            .collect();
+        assert_eq!(order, [GPIO_AFRH, GPIO_MODER]);
+    }
+
+    #[test]
+    fn invalid_alt_fn_leaves_mode() {
+        sim::reset();
+        let mut pin = Pin::new(Port::C, 3, PinMode::Input);
+        sim::clear_writes();
+
+        assert_eq!(pin.mode(PinMode::Alt(16)), Err(Error::InvalidAltFn(16)));
+        assert!(sim::writes().is_empty());
+    }
+
+    #[test]
+    fn try_new_rejects_alt_fn() {
+        sim::reset();
+        let result = Pin::try_new(Port::D, 2, PinMode::Alt(16));
+        assert!(matches!(result, Err(Error::InvalidAltFn(16))));
+        // Not even the port clock is enabled
+        assert!(sim::writes().is_empty());
+
+        let pin = Pin::try_new(Port::D, 2, PinMode::Alt(12)).unwrap();
+        assert_eq!(pin.port, Port::D);
+        assert_eq!((sim::read(Periph::Gpio(Port::D), GPIO_AFRL) >> 8) & 0xF, 12);
+    }
+
+    #[test]
+    fn set_high_and_low() {
+        sim::reset();
+        let mut pin = Pin::new(Port::B, 7, PinMode::Output);
+
+        pin.set_high();
+        assert!(pin.is_set_high());
+        assert_eq!(sim::read(Periph::Gpio(Port::B), GPIO_ODR), 1 << 7);
+        assert_eq!(sim::writes_to(Periph::Gpio(Port::B), GPIO_BSRR)[0].new, 1 << 7);
+
+        pin.toggle();
+        assert!(pin.is_set_low());
+        assert_eq!(sim::read(Periph::Gpio(Port::B), GPIO_ODR), 0);
+    }
+
+    #[test]
+    fn reads_input_level() {
+        sim::reset();
+        let pin = Pin::new(Port::C, 13, PinMode::Input);
+        assert!(pin.is_low());
+
+        sim::write(Periph::Gpio(Port::C), GPIO_IDR, 1 << 13);
+        assert!(pin.is_high());
+    }
+
+    #[test]
+    fn enable_interrupt_routes_line() {
+        sim::reset();
+        let mut pin = Pin::new(Port::C, 13, PinMode::Input);
+        pin.enable_interrupt(Edge::Falling);
+
+        // EXTICR4 holds lines 12 - 15
+        let exticr4 = sim::read(Periph::Syscfg, SYSCFG_EXTICR1 + 0xC);
+        assert_eq!((exticr4 >> 4) & 0xF, 2);
+        assert_eq!(sim::read(Periph::Exti, EXTI_FTSR1), 1 << 13);
+        assert_eq!(sim::read(Periph::Exti, EXTI_RTSR1), 0);
+        assert_eq!(sim::read(Periph::Exti, EXTI_IMR1), 1 << 13);
+        assert!(sim::is_unmasked(Interrupt::EXTI15_10));
+
+        pin.disable_interrupt();
+        assert_eq!(sim::read(Periph::Exti, EXTI_IMR1), 0);
+        assert!(!sim::is_unmasked(Interrupt::EXTI15_10));
+    }
+
+    #[test]
+    fn port_clocks_accumulate() {
+        sim::reset();
+        Pin::new(Port::A, 5, PinMode::Output);
+        Pin::new(Port::B, 7, PinMode::Output);
+        assert_eq!(sim::read(Periph::Rcc, RCC_AHB2ENR), 0b11);
+
+        // Port I is reset when first enabled
+        sim::clear_writes();
+        Pin::new(Port::I, 0, PinMode::Input);
+        assert_eq!(sim::read(Periph::Rcc, RCC_AHB2ENR), 0b1_0000_0011);
+        let resets = sim::writes_to(Periph::Rcc, RCC_AHB2RSTR);
+        assert_eq!(resets.iter().map(|w| w.new).collect::<Vec<_>>(), [1 << 8, 0]);
+        sim::clear_writes();
+        Pin::new(Port::I, 1, PinMode::Input);
+        assert!(sim::writes_to(Periph::Rcc, RCC_AHB2RSTR).is_empty());
+    }
+
+    #[test]
+    fn lock_key_sequence() {
+        sim::reset();
+        let pins = [Pin::new(Port::A, 3, PinMode::Output), Pin::new(Port::A, 5, PinMode::Output)];
+        sim::clear_writes();
+
+        let [mut a3, _] = lock_pins(pins).unwrap();
+        let mask = (1 << 3) | (1 << 5);
+        let writes: Vec<u32> = sim::writes_to(Periph::Gpio(Port::A), GPIO_LCKR).iter().map(|w| w.new).collect();
+        assert_eq!(writes, [(1 << 16) | mask, mask, (1 << 16) | mask]);
+        a3.set_high();
+        assert!(a3.is_set_high());
+
+        // Already locked with these pins: no second key sequence
+        let pins = [Pin::new(Port::A, 5, PinMode::Output)];
+        sim::clear_writes();
+        assert!(lock_pins(pins).is_ok());
+        assert!(sim::writes_to(Periph::Gpio(Port::A), GPIO_LCKR).is_empty());
+        // A pin left out of the lock can't be locked any more
+        let pins = [Pin::new(Port::A, 6, PinMode::Output)];
+        assert!(matches!(lock_pins(pins), Err((Error::LockFailed, _))));
+    }
+
+    #[test]
+    fn lock_failures() {
+        sim::reset();
+        let pins = [Pin::new(Port::A, 3, PinMode::Output), Pin::new(Port::B, 3, PinMode::Output)];
+        sim::clear_writes();
+        let Err((Error::PortMismatch, [mut a3, b3])) = lock_pins(pins) else {
+            panic!("Pins on two ports were locked");
+        };
+        assert!(sim::writes().is_empty());
+        // The pins come back and can still be driven
+        a3.set_high();
+        assert!(a3.is_set_high());
+        assert_eq!(b3.port, Port::B);
+
+        // LCKK never reads back set, e.g. after a wrong sequence
+        sim::read_only(Periph::Gpio(Port::C), GPIO_LCKR, 1 << 16);
+        let pin = Pin::new(Port::C, 1, PinMode::Input);
+        let Err((Error::LockFailed, pin)) = pin.lock() else {
+            panic!("LCKK didn't stick but the pin was locked");
+        };
+        // Retrying with the returned pin fails the same way
+        assert!(matches!(pin.lock(), Err((Error::LockFailed, _))));
+    }
+}
@@ -0,0 +1,117 @@
+//! Alternate function mapping for the STM32L496.
+//!
+//! The table is generated by `build.rs` from `data/stm32l496_af.csv`, with every
+//! peripheral name checked against `STM32L496.svd`.
+//!
+//! The table only covers the timers, USART/UART/LPUART, SPI, I2C and CAN so far.
+//! Signals of other peripherals (SAI, SDMMC, QUADSPI, FMC, ...) aren't in
+//! `Signal`, so their pins need `PinMode::Alt` with the number from the
+//! datasheet instead of `alt_pin!` or `Pin::route`.
+
+use super::Port;
+
+include!(concat!(env!("OUT_DIR"), "/af_table.rs"));
+
+/// Look up the alternate function number that routes `signal` to `port`/`pin`.
+/// Returns `None` if the pin can't carry the signal.
+pub const fn lookup(port: Port, pin: u8, signal: Signal) -> Option<u8> {
+    let mut i = 0;
+    while i < AF_TABLE.len() {
+        let (p, n, af, s) = AF_TABLE[i];
+        if p as u8 == port as u8 && n == pin && s as u16 == signal as u16 {
+            return Some(af);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// All signals a pin can carry, with the alternate function number for each.
+pub fn signals(port: Port, pin: u8) -> impl Iterator<Item = (u8, Signal)> {
+    AF_TABLE
+        .iter()
+        .filter(move |(p, n, _, _)| *p == port && *n == pin)
+        .map(|&(_, _, af, signal)| (af, signal))
+}
+
+/// Create a `gpio::Pin` in alternate function mode routed to a peripheral signal.
+/// The mapping is checked at compile time, so a pin that can't carry the signal
+/// fails the build instead of producing a dead board.
+///
+/// ```no_run
+/// use stm32l4_hal::alt_pin;
+/// use stm32l4_hal::gpio::{Port, Signal};
+///
+/// let tx = alt_pin!(Port::A, 2, Signal::Usart2Tx);
+/// ```
+///
+/// USART2_TX isn't available on PA3, so this doesn't build:
+///
+/// ```compile_fail,E0080
+/// use stm32l4_hal::alt_pin;
+/// use stm32l4_hal::gpio::{Port, Signal};
+///
+/// let tx = alt_pin!(Port::A, 3, Signal::Usart2Tx);
+/// ```
+#[macro_export]
+macro_rules! alt_pin {
+    ($port:expr, $pin:expr, $signal:expr) => {
+        $crate::gpio::Pin::new(
+            $port,
+            $pin,
+            $crate::gpio::PinMode::Alt(const {
+                match $crate::gpio::af::lookup($port, $pin, $signal) {
+                    Some(af) => af,
+                    None => panic!("Signal can't be routed to this pin."),
+                }
+            }),
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::sim::{self, *};
+    use crate::access::Periph;
+
+    #[test]
+    fn datasheet_rows() {
+        assert_eq!(lookup(Port::A, 2, Signal::Usart2Tx), Some(7));
+        assert_eq!(lookup(Port::D, 5, Signal::Usart2Tx), Some(7));
+        assert_eq!(lookup(Port::B, 6, Signal::I2c1Scl), Some(4));
+        assert_eq!(lookup(Port::A, 5, Signal::Spi1Sck), Some(5));
+        assert_eq!(lookup(Port::A, 3, Signal::Tim2Ch4), Some(1));
+    }
+
+    #[test]
+    fn misrouted_signal() {
+        assert_eq!(lookup(Port::A, 3, Signal::Usart2Tx), None);
+        assert_eq!(lookup(Port::B, 7, Signal::I2c1Scl), None);
+        assert_eq!(lookup(Port::A, 2, Signal::I2c1Scl), None);
+    }
+
+    #[test]
+    fn pin_signals() {
+        let routes: Vec<_> = signals(Port::A, 3).collect();
+        assert_eq!(
+            routes,
+            [
+                (1, Signal::Tim2Ch4),
+                (2, Signal::Tim5Ch4),
+                (7, Signal::Usart2Rx),
+                (8, Signal::Lpuart1Rx),
+                (14, Signal::Tim15Ch2),
+            ]
+        );
+        assert_eq!(signals(Port::H, 15).count(), 0);
+    }
+
+    #[test]
+    fn alt_pin_sets_table_af() {
+        sim::reset();
+        crate::alt_pin!(Port::B, 6, Signal::I2c1Scl);
+        assert_eq!((sim::read(Periph::Gpio(Port::B), GPIO_AFRL) >> 24) & 0xF, 4);
+        assert_eq!((sim::read(Periph::Gpio(Port::B), GPIO_MODER) >> 12) & 0b11, 0b10);
+    }
+}
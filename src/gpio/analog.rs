@@ -0,0 +1,134 @@
+//! Analog switch (`ASCR`) handling for ADC inputs.
+//!
+//! On the STM32L496 a pin in analog mode only reaches the ADC once its analog
+//! switch in `GPIOx_ASCR` is closed. The ADC inputs are PC0 - PC3 (ADC1, ADC2
+//! and ADC3), PA0 - PA7, PB0 - PB1 and PC4 - PC5 (ADC1 and ADC2), and PF3 - PF10
+//! (ADC3 only).
+
+use super::{Error, Pin, PinMode, Port};
+
+/// The ADCs an input is wired to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AdcInstance {
+    /// ADC1 and ADC2 (`ADC12_INx`).
+    Adc12,
+    /// ADC1, ADC2 and ADC3 (`ADC123_INx`).
+    Adc123,
+    /// ADC3 only (`ADC3_INx`).
+    Adc3,
+}
+
+impl AdcInstance {
+    /// Whether ADC1 (`1`), ADC2 (`2`) or ADC3 (`3`) can sample the input.
+    pub const fn includes(&self, adc: u8) -> bool {
+        match self {
+            Self::Adc12 => matches!(adc, 1 | 2),
+            Self::Adc123 => matches!(adc, 1..=3),
+            Self::Adc3 => adc == 3,
+        }
+    }
+}
+
+/// ADCs and channel (`ADCx_INy`) wired to a pin, or `None` if the pin isn't an
+/// ADC input.
+pub const fn adc_channel(port: Port, pin: u8) -> Option<(AdcInstance, u8)> {
+    match (port, pin) {
+        (Port::C, 0..=3) => Some((AdcInstance::Adc123, pin + 1)),
+        (Port::A, 0..=7) => Some((AdcInstance::Adc12, pin + 5)),
+        (Port::C, 4..=5) => Some((AdcInstance::Adc12, pin + 9)),
+        (Port::B, 0..=1) => Some((AdcInstance::Adc12, pin + 15)),
+        (Port::F, 3..=10) => Some((AdcInstance::Adc3, pin + 3)),
+        _ => None,
+    }
+}
+
+/// A pin in analog mode with its analog switch connected to the ADC. ADC drivers
+/// should only accept pins implementing this.
+pub trait AdcPin {
+    /// The ADCs the pin is wired to.
+    fn instance(&self) -> AdcInstance;
+
+    /// Channel of the pin on those ADCs.
+    fn channel(&self) -> u8;
+}
+
+/// A dynamic `gpio::Pin` in analog mode with the analog switch connected.
+pub struct AdcInputPin {
+    pin: Pin,
+}
+
+impl AdcInputPin {
+    /// Disconnect the analog switch and return the plain pin.
+    pub fn into_pin(mut self) -> Pin {
+        // Only ADC pins can be in an `AdcInputPin`, so this can't fail
+        let _ = self.pin.analog_switch(false);
+        self.pin
+    }
+}
+
+impl AdcInputPin {
+    fn adc_channel(&self) -> (AdcInstance, u8) {
+        adc_channel(self.pin.port, self.pin.pin).expect("AdcInputPin is always an ADC pin.")
+    }
+}
+
+impl AdcPin for AdcInputPin {
+    fn instance(&self) -> AdcInstance {
+        self.adc_channel().0
+    }
+
+    fn channel(&self) -> u8 {
+        self.adc_channel().1
+    }
+}
+
+impl Pin {
+    /// Connect or disconnect the pin's analog switch to the ADC. Sets `ASCR`.
+    pub fn analog_switch(&mut self, connect: bool) -> Result<(), Error> {
+        if adc_channel(self.port, self.pin).is_none() {
+            return Err(Error::NoAdcChannel);
+        }
+
+        let mask = 1 << self.pin;
+        self.regs()
+            .ascr()
+            .modify(|r, w| unsafe { w.bits(if connect { r.bits() | mask } else { r.bits() & !mask }) });
+        Ok(())
+    }
+
+    /// Put the pin in analog mode and connect it to the ADC.
+    pub fn into_adc_input(mut self) -> Result<AdcInputPin, Error> {
+        if adc_channel(self.port, self.pin).is_none() {
+            return Err(Error::NoAdcChannel);
+        }
+
+        self.mode(PinMode::Analog)?;
+        self.analog_switch(true)?;
+        Ok(AdcInputPin { pin: self })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::sim::{self, *};
+    use crate::access::Periph;
+
+    #[test]
+    fn adc3_input() {
+        sim::reset();
+        let pin = Pin::new(Port::F, 3, PinMode::Input).into_adc_input().unwrap();
+        assert_eq!(pin.instance(), AdcInstance::Adc3);
+        assert_eq!(pin.channel(), 6);
+        assert_eq!(sim::read(Periph::Gpio(Port::F), GPIO_ASCR), 1 << 3);
+        assert_eq!((sim::read(Periph::Gpio(Port::F), GPIO_MODER) >> 6) & 0b11, 0b11);
+
+        pin.into_pin();
+        assert_eq!(sim::read(Periph::Gpio(Port::F), GPIO_ASCR), 0);
+        assert_eq!(adc_channel(Port::F, 10), Some((AdcInstance::Adc3, 13)));
+        assert_eq!(adc_channel(Port::F, 2), None);
+        assert_eq!(adc_channel(Port::C, 0), Some((AdcInstance::Adc123, 1)));
+        assert!(AdcInstance::Adc123.includes(3));
+        assert!(!AdcInstance::Adc12.includes(3));
+    }
+}
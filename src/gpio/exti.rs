@@ -0,0 +1,299 @@
+//! Async edge waiting on the EXTI lines.
+//!
+//! Implements `embedded_hal_async::digital::Wait` for `gpio::Pin`. Each of the 16
+//! EXTI lines has a waker slot. The EXTI vectors must call `on_interrupt()`,
+//! e.g. from RTIC hardware tasks:
+//!
+//! ```ignore
+//! #[task(binds = EXTI15_10)]
+//! fn exti15_10(_: exti15_10::Context) {
+//!     stm32l4_hal::gpio::exti::on_interrupt();
+//! }
+//! ```
+//!
+//! Only lines with a pending `wait_for_*` future are serviced, so lines set up
+//! with `Pin::enable_interrupt` can still be handled by the application.
+//!
+//! EXTI line n is shared by pin n of every port, and `SYSCFG_EXTICR` routes it
+//! to one of them. Only one pin per line can be waited on at a time: while PA3
+//! is waited on, waiting on PB3 fails with `Error::ExtiLineInUse`.
+
+use core::future::Future;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll};
+
+use embedded_hal_async::digital::Wait;
+use rtic_common::waker_registration::CriticalSectionWakerRegistration;
+
+use super::{Edge, Error, Pin};
+use crate::access;
+
+static WAKERS: [CriticalSectionWakerRegistration; 16] =
+    [const { CriticalSectionWakerRegistration::new() }; 16];
+
+// EXTI lines with a future waiting on them. Cleared by `on_interrupt` when the
+// edge arrives.
+static WAITING: AtomicU32 = AtomicU32::new(0);
+
+// EXTI lines with a live future, until it is dropped
+static CLAIMED: AtomicU32 = AtomicU32::new(0);
+
+/// Service the EXTI lines that have a future waiting on them. Call this from the
+/// `EXTI0` - `EXTI4`, `EXTI9_5` and `EXTI15_10` handlers; the shared vectors
+/// wake every pending line in their group.
+pub fn on_interrupt() {
+    let exti = access::exti();
+
+    let fired = exti.pr1().read().bits() & WAITING.load(Ordering::SeqCst) & 0xFFFF;
+    if fired == 0 {
+        return;
+    }
+
+    // Mask the lines so they don't fire again before the futures are polled
+    unsafe {
+        exti.imr1().modify(|r, w| w.bits(r.bits() & !fired));
+        exti.pr1().write(|w| w.bits(fired));
+    }
+    WAITING.fetch_and(!fired, Ordering::SeqCst);
+
+    for (line, waker) in WAKERS.iter().enumerate() {
+        if fired & (1 << line) != 0 {
+            waker.wake();
+        }
+    }
+}
+
+// Resolves once `on_interrupt` sees the edge on the pin's line. Dropping it
+// disables the interrupt again.
+struct ExtiFuture<'a> {
+    pin: &'a mut Pin,
+}
+
+impl<'a> ExtiFuture<'a> {
+    // Fails if another future already holds the line, which may be routed to
+    // a different port
+    fn new(pin: &'a mut Pin, edge: Edge) -> Result<Self, Error> {
+        let line = 1 << pin.pin;
+        if CLAIMED.fetch_or(line, Ordering::SeqCst) & line != 0 {
+            return Err(Error::ExtiLineInUse(pin.pin));
+        }
+
+        WAITING.fetch_or(line, Ordering::SeqCst);
+        pin.enable_interrupt(edge);
+        Ok(Self { pin })
+    }
+}
+
+impl Future for ExtiFuture<'_> {
+    type Output = ();
+
+    fn poll(self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let line = self.pin.pin;
+        // Register before checking so an edge in between isn't missed
+        WAKERS[line as usize].register(cx.waker());
+
+        if WAITING.load(Ordering::SeqCst) & (1 << line) == 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for ExtiFuture<'_> {
+    fn drop(&mut self) {
+        WAITING.fetch_and(!(1 << self.pin.pin), Ordering::SeqCst);
+        self.pin.disable_interrupt();
+        CLAIMED.fetch_and(!(1 << self.pin.pin), Ordering::SeqCst);
+    }
+}
+
+/// Each wait fails with `Error::ExtiLineInUse` while another pin with the same
+/// number is being waited on, see the module docs.
+impl Wait for Pin {
+    async fn wait_for_high(&mut self) -> Result<(), Error> {
+        // Arm the interrupt before checking the level so a rising edge in
+        // between isn't lost
+        let edge = ExtiFuture::new(self, Edge::Rising)?;
+        if edge.pin.is_high() {
+            return Ok(());
+        }
+        edge.await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Error> {
+        let edge = ExtiFuture::new(self, Edge::Falling)?;
+        if edge.pin.is_low() {
+            return Ok(());
+        }
+        edge.await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Error> {
+        ExtiFuture::new(self, Edge::Rising)?.await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Error> {
+        ExtiFuture::new(self, Edge::Falling)?.await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Error> {
+        ExtiFuture::new(self, Edge::Either)?.await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::sim::{self, *};
+    use crate::access::Periph;
+    use crate::gpio::{PinMode, Port};
+    use crate::pac::Interrupt;
+
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    // Lines are global, so each test uses its own
+
+    #[derive(Default)]
+    struct Wakes(AtomicUsize);
+
+    impl Wake for Wakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn poll<F: Future>(future: core::pin::Pin<&mut F>, wakes: &Arc<Wakes>) -> Poll<F::Output> {
+        let waker = Waker::from(wakes.clone());
+        future.poll(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn edge_wakes_and_clears() {
+        sim::reset();
+        let mut pin = Pin::new(Port::C, 13, PinMode::Input);
+        let wakes = Arc::new(Wakes::default());
+        let mut future = core::pin::pin!(pin.wait_for_rising_edge());
+
+        assert!(poll(future.as_mut(), &wakes).is_pending());
+        assert_eq!(sim::read(Periph::Exti, EXTI_IMR1), 1 << 13);
+        assert_eq!(sim::read(Periph::Exti, EXTI_PR1), 0);
+
+        // Line 3 belongs to the application and stays pending
+        sim::write(Periph::Exti, EXTI_PR1, (1 << 13) | (1 << 3));
+        on_interrupt();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert_eq!(sim::read(Periph::Exti, EXTI_PR1), 1 << 3);
+        assert_eq!(sim::read(Periph::Exti, EXTI_IMR1), 0);
+
+        assert!(poll(future.as_mut(), &wakes).is_ready());
+    }
+
+    #[test]
+    fn shared_vectors() {
+        sim::reset();
+        let mut pins = [(Port::A, 6), (Port::B, 8), (Port::A, 11), (Port::B, 14)]
+            .map(|(port, line)| Pin::new(port, line, PinMode::Input));
+        let wakes: [Arc<Wakes>; 4] = Default::default();
+        let [a, b, c, d] = &mut pins;
+        let mut futures = [
+            Box::pin(a.wait_for_falling_edge()),
+            Box::pin(b.wait_for_falling_edge()),
+            Box::pin(c.wait_for_falling_edge()),
+            Box::pin(d.wait_for_falling_edge()),
+        ];
+        for (future, wakes) in futures.iter_mut().zip(&wakes) {
+            assert!(poll(future.as_mut(), wakes).is_pending());
+        }
+        assert!(sim::is_unmasked(Interrupt::EXTI9_5));
+        assert!(sim::is_unmasked(Interrupt::EXTI15_10));
+
+        // EXTI9_5 fires for both of its lines
+        sim::write(Periph::Exti, EXTI_PR1, (1 << 6) | (1 << 8));
+        on_interrupt();
+        let count = |wakes: &[Arc<Wakes>; 4]| wakes.each_ref().map(|wakes| wakes.0.load(Ordering::SeqCst));
+        assert_eq!(count(&wakes), [1, 1, 0, 0]);
+        assert_eq!(sim::read(Periph::Exti, EXTI_IMR1), (1 << 11) | (1 << 14));
+
+        sim::write(Periph::Exti, EXTI_PR1, 1 << 14);
+        on_interrupt();
+        assert_eq!(count(&wakes), [1, 1, 0, 1]);
+        assert_eq!(sim::read(Periph::Exti, EXTI_IMR1), 1 << 11);
+        assert!(poll(futures[3].as_mut(), &wakes[3]).is_ready());
+        assert!(poll(futures[2].as_mut(), &wakes[2]).is_pending());
+    }
+
+    #[test]
+    fn level_already_matches() {
+        sim::reset();
+        let mut pin = Pin::new(Port::A, 2, PinMode::Input);
+        sim::write(Periph::Gpio(Port::A), GPIO_IDR, 1 << 2);
+        let wakes = Arc::new(Wakes::default());
+
+        assert!(poll(core::pin::pin!(pin.wait_for_high()), &wakes).is_ready());
+        // The interrupt armed for the edge is off again
+        assert_eq!(sim::read(Periph::Exti, EXTI_IMR1), 0);
+        assert!(!sim::is_unmasked(Interrupt::EXTI2));
+        assert_eq!(WAITING.load(Ordering::SeqCst) & (1 << 2), 0);
+    }
+
+    #[test]
+    fn drop_disables_line() {
+        sim::reset();
+        let mut pin = Pin::new(Port::A, 4, PinMode::Input);
+        let wakes = Arc::new(Wakes::default());
+        {
+            let mut future = core::pin::pin!(pin.wait_for_any_edge());
+            assert!(poll(future.as_mut(), &wakes).is_pending());
+            assert_eq!(sim::read(Periph::Exti, EXTI_IMR1), 1 << 4);
+            assert_eq!(sim::read(Periph::Exti, EXTI_RTSR1), 1 << 4);
+            assert_eq!(sim::read(Periph::Exti, EXTI_FTSR1), 1 << 4);
+        }
+
+        assert_eq!(sim::read(Periph::Exti, EXTI_IMR1), 0);
+        assert_eq!(sim::read(Periph::Exti, EXTI_RTSR1), 0);
+        assert!(!sim::is_unmasked(Interrupt::EXTI4));
+        assert_eq!(WAITING.load(Ordering::SeqCst) & (1 << 4), 0);
+        // A late edge wakes nothing
+        sim::write(Periph::Exti, EXTI_PR1, 1 << 4);
+        on_interrupt();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn line_shared_across_ports() {
+        sim::reset();
+        let mut a9 = Pin::new(Port::A, 9, PinMode::Input);
+        let mut b9 = Pin::new(Port::B, 9, PinMode::Input);
+        let wakes = Arc::new(Wakes::default());
+        // EXTICR3 holds lines 8 - 11
+        let exticr3 = || (sim::read(Periph::Syscfg, SYSCFG_EXTICR1 + 0x8) >> 4) & 0xF;
+        {
+            let mut first = core::pin::pin!(a9.wait_for_rising_edge());
+            assert!(poll(first.as_mut(), &wakes).is_pending());
+
+            // PB9 can't take line 9 away from PA9
+            let second = poll(core::pin::pin!(b9.wait_for_rising_edge()), &wakes);
+            assert_eq!(second, Poll::Ready(Err(Error::ExtiLineInUse(9))));
+            assert_eq!(exticr3(), 0);
+            assert_eq!(sim::read(Periph::Exti, EXTI_IMR1), 1 << 9);
+
+            sim::write(Periph::Exti, EXTI_PR1, 1 << 9);
+            on_interrupt();
+            assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+            assert_eq!(poll(first.as_mut(), &wakes), Poll::Ready(Ok(())));
+        }
+
+        // Free again once the first wait is gone
+        let mut second = core::pin::pin!(b9.wait_for_rising_edge());
+        assert!(poll(second.as_mut(), &wakes).is_pending());
+        assert_eq!(exticr3(), 1);
+    }
+}
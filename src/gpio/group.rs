@@ -0,0 +1,239 @@
+//! Port-wide operations on a group of pins, for parallel buses.
+//!
+//! Values are bit-per-pin at the pin positions, e.g. bit 3 of `write`'s value
+//! drives pin 3 of the port. Bits outside the group's mask are ignored.
+
+use super::{enable_port_clock, regs, Error, OutputSpeed, OutputType, Pin, PinMode, Port, Pull};
+use crate::access::Block;
+use crate::pac;
+
+/// A set of pins on one port, read and written together.
+pub struct PinGroup {
+    port: Port,
+    mask: u16,
+}
+
+impl PinGroup {
+    /// Create a group from a mask of pins on `port`, and set them all to `mode`.
+    /// Prefer `from_pins`, which takes the pins it drives.
+    ///
+    /// # Safety
+    ///
+    /// The caller must own every pin in `mask`: none of them may be in use as a
+    /// `Pin`, a typed pin from `GpioExt::split` or another group.
+    pub unsafe fn new(port: Port, mask: u16, mode: PinMode) -> Result<Self, Error> {
+        enable_port_clock(port);
+
+        let mut result = Self { port, mask };
+        result.mode(mode)?;
+        Ok(result)
+    }
+
+    /// Create a group from pins that must all be on the same port. The pins keep
+    /// their current configuration. On `PortMismatch` the pins are handed back
+    /// with the error.
+    pub fn from_pins<const N: usize>(pins: [Pin; N]) -> Result<Self, (Error, [Pin; N])> {
+        let Some(first) = pins.first() else {
+            return Err((Error::PortMismatch, pins));
+        };
+        if pins.iter().any(|pin| pin.port != first.port) {
+            return Err((Error::PortMismatch, pins));
+        }
+
+        let mask = pins.iter().fold(0, |mask, pin| mask | (1 << pin.pin));
+        Ok(Self { port: first.port, mask })
+    }
+
+    pub fn port(&self) -> Port {
+        self.port
+    }
+
+    pub fn mask(&self) -> u16 {
+        self.mask
+    }
+
+    /// Set the mode of every pin in the group. Sets `MODER`, and `AFRL`/`AFRH`
+    /// for `PinMode::Alt`.
+    pub fn mode(&mut self, value: PinMode) -> Result<(), Error> {
+        if let PinMode::Alt(alt) = value {
+            if alt > 15 {
+                return Err(Error::InvalidAltFn(alt));
+            }
+            let (low, high) = spread4(self.mask);
+            let alt = alt as u32;
+            unsafe {
+                self.regs().afrl().modify(|r, w| w.bits(replace(r.bits(), low, alt * 0x1111_1111)));
+                self.regs().afrh().modify(|r, w| w.bits(replace(r.bits(), high, alt * 0x1111_1111)));
+            }
+        }
+
+        let fields = spread2(self.mask);
+        let value = value.val() as u32 * 0x5555_5555;
+        unsafe {
+            self.regs().moder().modify(|r, w| w.bits(replace(r.bits(), fields, value)));
+        }
+        Ok(())
+    }
+
+    /// Sets `OTYPER` for every pin in the group.
+    pub fn output_type(&mut self, value: OutputType) {
+        let value = match value {
+            OutputType::PushPull => 0,
+            OutputType::OpenDrain => 0xFFFF,
+        };
+        let mask = self.mask as u32;
+        unsafe {
+            self.regs().otyper().modify(|r, w| w.bits(replace(r.bits(), mask, value)));
+        }
+    }
+
+    /// Sets `OSPEEDR` for every pin in the group.
+    pub fn output_speed(&mut self, value: OutputSpeed) {
+        let fields = spread2(self.mask);
+        let value = value as u32 * 0x5555_5555;
+        unsafe {
+            self.regs().ospeedr().modify(|r, w| w.bits(replace(r.bits(), fields, value)));
+        }
+    }
+
+    /// Sets `PUPDR` for every pin in the group.
+    pub fn pull(&mut self, value: Pull) {
+        let fields = spread2(self.mask);
+        let value = value as u32 * 0x5555_5555;
+        unsafe {
+            self.regs().pupdr().modify(|r, w| w.bits(replace(r.bits(), fields, value)));
+        }
+    }
+
+    /// Drive every pin in the group at once with a single `BSRR` write.
+    pub fn write(&mut self, value: u16) {
+        let set = value & self.mask;
+        let reset = !value & self.mask;
+        unsafe {
+            self.regs().bsrr().write(|w| w.bits(((reset as u32) << 16) | set as u32));
+        }
+    }
+
+    /// Read the input level of every pin in the group from `IDR`.
+    pub fn read(&self) -> u16 {
+        self.regs().idr().read().bits() as u16 & self.mask
+    }
+
+    /// Read the output latch of every pin in the group from `ODR`.
+    pub fn read_output(&self) -> u16 {
+        self.regs().odr().read().bits() as u16 & self.mask
+    }
+
+    /// Update the outputs from their current latched value. The new value is
+    /// written with a single `BSRR` write, in the same critical section as the
+    /// `ODR` read so an interrupt can't change a pin in between.
+    pub fn modify<F>(&mut self, f: F)
+    where
+        F: FnOnce(u16) -> u16,
+    {
+        critical_section::with(|_| {
+            let value = f(self.read_output());
+            self.write(value);
+        });
+    }
+
+    fn regs(&self) -> Block<pac::gpioa::RegisterBlock> {
+        regs(self.port)
+    }
+}
+
+// Replace the `fields` bits of `bits` with `value`
+fn replace(bits: u32, fields: u32, value: u32) -> u32 {
+    (bits & !fields) | (value & fields)
+}
+
+// Expand a pin mask to the 2-bit fields of `MODER`, `OSPEEDR` and `PUPDR`
+fn spread2(mask: u16) -> u32 {
+    (0..16)
+        .filter(|pin| mask & (1 << pin) != 0)
+        .fold(0, |fields, pin| fields | (0b11 << (pin * 2)))
+}
+
+// Expand a pin mask to the 4-bit fields of `AFRL` (pins 0 - 7) and `AFRH` (pins 8 - 15)
+fn spread4(mask: u16) -> (u32, u32) {
+    let fields = |byte: u16| {
+        (0..8)
+            .filter(|pin| byte & (1 << pin) != 0)
+            .fold(0, |fields, pin| fields | (0xF << (pin * 4)))
+    };
+    (fields(mask & 0xFF), fields(mask >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::sim::{self, *};
+    use crate::access::Periph;
+
+    #[test]
+    fn from_pins_needs_one_port() {
+        sim::reset();
+        let pins = [Pin::new(Port::A, 1, PinMode::Output), Pin::new(Port::B, 1, PinMode::Output)];
+        let Err((Error::PortMismatch, [mut a1, b1])) = PinGroup::from_pins(pins) else {
+            panic!("Pins on two ports were grouped");
+        };
+        // The pins come back and can still be driven
+        a1.set_high();
+        assert!(a1.is_set_high());
+        assert_eq!(b1.port, Port::B);
+        assert!(matches!(PinGroup::from_pins([]), Err((Error::PortMismatch, []))));
+
+        let pins = [Pin::new(Port::C, 2, PinMode::Output), Pin::new(Port::C, 9, PinMode::Output)];
+        let group = PinGroup::from_pins(pins).unwrap();
+        assert_eq!(group.port(), Port::C);
+        assert_eq!(group.mask(), (1 << 2) | (1 << 9));
+    }
+
+    #[test]
+    fn config_spreads_to_fields() {
+        sim::reset();
+        let mask = 0b1000_0000_1010_0101;
+        let fields = 0xC000_CC33;
+        let mut group = unsafe { PinGroup::new(Port::D, mask, PinMode::Output) }.unwrap();
+        group.pull(Pull::Up);
+        group.output_speed(OutputSpeed::High);
+
+        let port = Periph::Gpio(Port::D);
+        // Pins outside the mask keep their reset analog mode
+        assert_eq!(sim::read(port, GPIO_MODER), (0x5555_5555 & fields) | !fields);
+        assert_eq!(sim::read(port, GPIO_PUPDR), 0x5555_5555 & fields);
+        assert_eq!(sim::read(port, GPIO_OSPEEDR), 0xAAAA_AAAA & fields);
+
+        group.mode(PinMode::Alt(5)).unwrap();
+        assert_eq!(sim::read(port, GPIO_AFRL), 0x5050_0505);
+        assert_eq!(sim::read(port, GPIO_AFRH), 0x5000_0000);
+        assert_eq!(sim::read(port, GPIO_MODER), (0xAAAA_AAAA & fields) | !fields);
+    }
+
+    #[test]
+    fn write_is_one_bsrr_word() {
+        sim::reset();
+        let mut group = unsafe { PinGroup::new(Port::E, 0b1111_0000, PinMode::Output) }.unwrap();
+        sim::clear_writes();
+
+        group.write(0b1010_1111);
+        let writes: Vec<u32> = sim::writes_to(Periph::Gpio(Port::E), GPIO_BSRR).iter().map(|w| w.new).collect();
+        assert_eq!(writes, [(0b0101_0000 << 16) | 0b1010_0000]);
+        assert_eq!(sim::read(Periph::Gpio(Port::E), GPIO_ODR), 0b1010_0000);
+        assert_eq!(group.read_output(), 0b1010_0000);
+    }
+
+    #[test]
+    fn modify_keeps_other_pins() {
+        sim::reset();
+        let mut group = unsafe { PinGroup::new(Port::F, 0b0000_1111, PinMode::Output) }.unwrap();
+        sim::write(Periph::Gpio(Port::F), GPIO_ODR, 0b1010_0101_0011);
+        sim::clear_writes();
+
+        group.modify(|value| !value);
+        assert_eq!(sim::read(Periph::Gpio(Port::F), GPIO_ODR), 0b1010_0101_1100);
+        // One BSRR word that only names the group's pins
+        let writes: Vec<u32> = sim::writes_to(Periph::Gpio(Port::F), GPIO_BSRR).iter().map(|w| w.new).collect();
+        assert_eq!(writes, [(0b0011 << 16) | 0b1100]);
+    }
+}
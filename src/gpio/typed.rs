@@ -0,0 +1,425 @@
+//! Typestate GPIO pins.
+//!
+//! Each pin is a zero-sized type carrying its port, number and mode, so using a
+//! pin in the wrong mode is a compile error and register access needs no runtime
+//! `match`. Pins are handed out by `GpioExt::split()`, which consumes the port so
+//! each line has exactly one owner. Use `erase()` to get back a dynamic `gpio::Pin`.
+
+use core::convert::Infallible;
+use core::marker::PhantomData;
+
+use embedded_hal::digital::{ErrorType, InputPin, OutputPin, StatefulOutputPin};
+
+use super::analog::{adc_channel, AdcInstance, AdcPin};
+use super::{enable_port_clock, regs, OutputSpeed, Port};
+use crate::access::Block;
+use crate::pac;
+
+use paste::paste;
+
+/// Input mode (type state).
+pub struct Input<PULL> {
+    _pull: PhantomData<PULL>,
+}
+
+/// Floating input (type state).
+pub struct Floating;
+/// Pulled up input (type state).
+pub struct PullUp;
+/// Pulled down input (type state).
+pub struct PullDown;
+
+/// Output mode (type state).
+pub struct Output<OTYPE> {
+    _otype: PhantomData<OTYPE>,
+}
+
+/// Push pull output (type state).
+pub struct PushPull;
+/// Open drain output (type state).
+pub struct OpenDrain;
+
+/// Alternate function `AF` mode (type state).
+pub struct Alternate<const AF: u8, OTYPE = PushPull> {
+    _otype: PhantomData<OTYPE>,
+}
+
+/// Analog mode (type state).
+pub struct Analog;
+
+/// Analog mode with the analog switch connected to the ADC (type state).
+pub struct AdcInput;
+
+/// GPIO pin `N` of port `P` in mode `MODE`.
+pub struct Pin<const P: char, const N: u8, MODE> {
+    _mode: PhantomData<MODE>,
+}
+
+impl<const P: char, const N: u8, MODE> Pin<P, N, MODE> {
+    const PORT: Port = match P {
+        'A' => Port::A,
+        'B' => Port::B,
+        'C' => Port::C,
+        'D' => Port::D,
+        'E' => Port::E,
+        'F' => Port::F,
+        'G' => Port::G,
+        'H' => Port::H,
+        'I' => Port::I,
+        _ => panic!("GPIO ports must be A - I."),
+    };
+
+    const PIN: u8 = {
+        assert!(N <= 15, "GPIO pins must be 0 - 15.");
+        N
+    };
+
+    // Only `GpioExt::split` hands out pins, so each line has a single owner
+    const fn new() -> Self {
+        Pin { _mode: PhantomData }
+    }
+
+    const fn new_mode<NEW>() -> Pin<P, N, NEW> {
+        Pin { _mode: PhantomData }
+    }
+
+    fn regs() -> Block<pac::gpioa::RegisterBlock> {
+        regs(Self::PORT)
+    }
+
+    fn set_moder(value: u8) {
+        Self::regs().moder().modify(|_, w| w.moder(Self::PIN).set(value));
+    }
+
+    fn set_pull(value: u8) {
+        Self::regs().pupdr().modify(|_, w| unsafe { w.pupdr(Self::PIN).bits(value) });
+    }
+
+    fn set_open_drain(value: bool) {
+        Self::regs().otyper().modify(|_, w| w.ot(Self::PIN).bit(value));
+    }
+
+    fn set_af(value: u8) {
+        if Self::PIN < 8 {
+            Self::regs().afrl().modify(|_, w| w.afr(Self::PIN).set(value));
+        } else {
+            Self::regs().afrh().modify(|_, w| w.afr(Self::PIN - 8).set(value));
+        }
+    }
+
+    /// Configure the pin as a floating input.
+    pub fn into_floating_input(self) -> Pin<P, N, Input<Floating>> {
+        Self::set_pull(0b00);
+        Self::set_moder(0b00);
+        Self::new_mode()
+    }
+
+    /// Configure the pin as an input with the pull up enabled.
+    pub fn into_pull_up_input(self) -> Pin<P, N, Input<PullUp>> {
+        Self::set_pull(0b01);
+        Self::set_moder(0b00);
+        Self::new_mode()
+    }
+
+    /// Configure the pin as an input with the pull down enabled.
+    pub fn into_pull_down_input(self) -> Pin<P, N, Input<PullDown>> {
+        Self::set_pull(0b10);
+        Self::set_moder(0b00);
+        Self::new_mode()
+    }
+
+    /// Configure the pin as a push pull output.
+    pub fn into_push_pull_output(self) -> Pin<P, N, Output<PushPull>> {
+        Self::set_open_drain(false);
+        Self::set_moder(0b01);
+        Self::new_mode()
+    }
+
+    /// Configure the pin as an open drain output.
+    pub fn into_open_drain_output(self) -> Pin<P, N, Output<OpenDrain>> {
+        Self::set_open_drain(true);
+        Self::set_moder(0b01);
+        Self::new_mode()
+    }
+
+    /// Configure the pin as push pull alternate function `AF`.
+    pub fn into_alternate<const AF: u8>(self) -> Pin<P, N, Alternate<AF, PushPull>> {
+        const { assert!(AF <= 15, "Alternate function must be 0 - 15.") };
+        // Select the function before the mode so the pin never drives a stale signal
+        Self::set_af(AF);
+        Self::set_open_drain(false);
+        Self::set_moder(0b10);
+        Self::new_mode()
+    }
+
+    /// Configure the pin as open drain alternate function `AF`.
+    pub fn into_alternate_open_drain<const AF: u8>(self) -> Pin<P, N, Alternate<AF, OpenDrain>> {
+        const { assert!(AF <= 15, "Alternate function must be 0 - 15.") };
+        Self::set_af(AF);
+        Self::set_open_drain(true);
+        Self::set_moder(0b10);
+        Self::new_mode()
+    }
+
+    fn set_analog_switch(connect: bool) {
+        let mask = 1 << Self::PIN;
+        Self::regs()
+            .ascr()
+            .modify(|r, w| unsafe { w.bits(if connect { r.bits() | mask } else { r.bits() & !mask }) });
+    }
+
+    /// Configure the pin as analog. ADC inputs have their analog switch
+    /// disconnected; use `into_adc_input` to sample the pin.
+    pub fn into_analog(self) -> Pin<P, N, Analog> {
+        Self::set_moder(0b11);
+        if adc_channel(Self::PORT, Self::PIN).is_some() {
+            Self::set_analog_switch(false);
+        }
+        Self::new_mode()
+    }
+
+    /// Configure the pin as analog and connect its analog switch to the ADC.
+    /// Only available on ADC inputs.
+    pub fn into_adc_input(self) -> Pin<P, N, AdcInput> {
+        const { assert!(adc_channel(Self::PORT, Self::PIN).is_some(), "Pin isn't an ADC input.") };
+        Self::set_moder(0b11);
+        Self::set_analog_switch(true);
+        Self::new_mode()
+    }
+
+    /// Erase the port, pin number and mode, returning a dynamic `gpio::Pin`.
+    /// The pin configuration is left as is.
+    pub fn erase(self) -> super::Pin {
+        super::Pin {
+            port: Self::PORT,
+            pin: Self::PIN,
+        }
+    }
+}
+
+impl<const P: char, const N: u8, PULL> Pin<P, N, Input<PULL>> {
+    pub fn is_high(&self) -> bool {
+        Self::regs().idr().read().idr(Self::PIN).bit_is_set()
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+impl<const P: char, const N: u8, OTYPE> Pin<P, N, Output<OTYPE>> {
+    pub fn set_high(&mut self) {
+        Self::regs().bsrr().write(|w| w.bs(Self::PIN).set_bit());
+    }
+
+    pub fn set_low(&mut self) {
+        Self::regs().bsrr().write(|w| w.br(Self::PIN).set_bit());
+    }
+
+    /// Whether the output latch (`ODR`) is set, regardless of the pin level.
+    pub fn is_set_high(&self) -> bool {
+        Self::regs().odr().read().odr(Self::PIN).bit_is_set()
+    }
+
+    pub fn is_set_low(&self) -> bool {
+        !self.is_set_high()
+    }
+
+    /// Toggle the output latch. Reads `ODR` and writes the matching `BSRR` word
+    /// inside a critical section, so an interrupt can't change the pin in between.
+    pub fn toggle(&mut self) {
+        critical_section::with(|_| {
+            let regs = Self::regs();
+            let mask = 1 << Self::PIN;
+            // Reset the pin if it is set, else set it
+            let bsrr = if regs.odr().read().bits() & mask != 0 { mask << 16 } else { mask };
+            regs.bsrr().write(|w| unsafe { w.bits(bsrr) });
+        });
+    }
+
+    pub fn set_speed(&mut self, value: OutputSpeed) {
+        Self::regs().ospeedr().modify(|_, w| w.ospeedr(Self::PIN).set(value as u8));
+    }
+}
+
+impl<const P: char, const N: u8, const AF: u8, OTYPE> Pin<P, N, Alternate<AF, OTYPE>> {
+    pub fn set_speed(&mut self, value: OutputSpeed) {
+        Self::regs().ospeedr().modify(|_, w| w.ospeedr(Self::PIN).set(value as u8));
+    }
+}
+
+impl<const P: char, const N: u8> AdcPin for Pin<P, N, AdcInput> {
+    fn instance(&self) -> AdcInstance {
+        const {
+            match adc_channel(Self::PORT, Self::PIN) {
+                Some((instance, _)) => instance,
+                None => panic!("Pin isn't an ADC input."),
+            }
+        }
+    }
+
+    fn channel(&self) -> u8 {
+        const {
+            match adc_channel(Self::PORT, Self::PIN) {
+                Some((_, channel)) => channel,
+                None => panic!("Pin isn't an ADC input."),
+            }
+        }
+    }
+}
+
+impl<const P: char, const N: u8, MODE> ErrorType for Pin<P, N, MODE> {
+    type Error = Infallible;
+}
+
+impl<const P: char, const N: u8, PULL> InputPin for Pin<P, N, Input<PULL>> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_high(self))
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_low(self))
+    }
+}
+
+impl<const P: char, const N: u8, OTYPE> OutputPin for Pin<P, N, Output<OTYPE>> {
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Pin::set_high(self);
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Pin::set_low(self);
+        Ok(())
+    }
+}
+
+impl<const P: char, const N: u8, OTYPE> StatefulOutputPin for Pin<P, N, Output<OTYPE>> {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_set_high(self))
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(Pin::is_set_low(self))
+    }
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        Pin::toggle(self);
+        Ok(())
+    }
+}
+
+// Aliases for every pin, e.g. `PB7<Output<PushPull>>`
+macro_rules! pin_aliases {
+    ($($port:ident: $c:literal),+) => {
+        $(
+            pin_aliases!(@port $port, $c, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        )+
+    };
+    (@port $port:ident, $c:literal, [$($num:literal),+]) => {
+        paste! {
+            $(
+                pub type [<P $port $num>]<MODE> = Pin<$c, $num, MODE>;
+            )+
+        }
+    };
+}
+
+pin_aliases!(A: 'A', B: 'B', C: 'C', D: 'D', E: 'E', F: 'F', G: 'G', H: 'H', I: 'I');
+
+/// Split a GPIO port into its independently owned pins.
+pub trait GpioExt {
+    /// The owned pins of the port.
+    type Parts;
+
+    /// Consume the port, enable its clock and hand out one pin per line.
+    fn split(self) -> Self::Parts;
+}
+
+// Pins are listed with their reset mode. Everything resets to analog except the
+// debug pins, which reset to AF0.
+macro_rules! gpio {
+    ($GPIOX:ident, $gpiox:ident, $port:ident: $c:literal, [$($num:literal: $MODE:ty),+]) => {
+        paste! {
+            pub mod $gpiox {
+                use super::*;
+
+                #[doc = "Owned pins of `" $GPIOX "`."]
+                pub struct Parts {
+                    $(
+                        pub [<p $port:lower $num>]: [<P $port $num>]<$MODE>,
+                    )+
+                }
+            }
+
+            impl GpioExt for pac::$GPIOX {
+                type Parts = $gpiox::Parts;
+
+                fn split(self) -> Self::Parts {
+                    enable_port_clock(Port::$port);
+
+                    $gpiox::Parts {
+                        $(
+                            [<p $port:lower $num>]: Pin::new(),
+                        )+
+                    }
+                }
+            }
+        }
+    };
+    ($GPIOX:ident, $gpiox:ident, $port:ident: $c:literal, [$($num:literal),+]) => {
+        gpio!($GPIOX, $gpiox, $port: $c, [$($num: Analog),+]);
+    };
+}
+
+gpio!(GPIOA, gpioa, A: 'A', [
+    0: Analog, 1: Analog, 2: Analog, 3: Analog, 4: Analog, 5: Analog, 6: Analog, 7: Analog,
+    8: Analog, 9: Analog, 10: Analog, 11: Analog, 12: Analog,
+    13: Alternate<0>, 14: Alternate<0>, 15: Alternate<0>
+]);
+gpio!(GPIOB, gpiob, B: 'B', [
+    0: Analog, 1: Analog, 2: Analog, 3: Alternate<0>, 4: Alternate<0>, 5: Analog, 6: Analog,
+    7: Analog, 8: Analog, 9: Analog, 10: Analog, 11: Analog, 12: Analog, 13: Analog,
+    14: Analog, 15: Analog
+]);
+gpio!(GPIOC, gpioc, C: 'C', [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+gpio!(GPIOD, gpiod, D: 'D', [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+gpio!(GPIOE, gpioe, E: 'E', [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+gpio!(GPIOF, gpiof, F: 'F', [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+gpio!(GPIOG, gpiog, G: 'G', [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+gpio!(GPIOH, gpioh, H: 'H', [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+// Port I only has 12 lines on the L496
+gpio!(GPIOI, gpioi, I: 'I', [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::sim::{self, *};
+    use crate::access::Periph;
+
+    #[test]
+    fn typed_adc3_input() {
+        sim::reset();
+        let pin = Pin::<'F', 3, Analog>::new().into_adc_input();
+        assert_eq!(pin.instance(), AdcInstance::Adc3);
+        assert_eq!(pin.channel(), 6);
+        assert_eq!(sim::read(Periph::Gpio(Port::F), GPIO_ASCR), 1 << 3);
+    }
+
+    #[test]
+    fn toggle_writes_bsrr_once() {
+        sim::reset();
+        let mut pin = Pin::<'B', 7, Output<PushPull>>::new();
+        pin.set_high();
+        sim::clear_writes();
+
+        pin.toggle();
+        assert!(pin.is_set_low());
+        assert_eq!(sim::writes_to(Periph::Gpio(Port::B), GPIO_BSRR).len(), 1);
+        assert_eq!(sim::writes_to(Periph::Gpio(Port::B), GPIO_BSRR)[0].new, 1 << 23);
+
+        StatefulOutputPin::toggle(&mut pin).unwrap();
+        assert!(pin.is_set_high());
+        assert_eq!(sim::read(Periph::Gpio(Port::B), GPIO_ODR), 1 << 7);
+    }
+}
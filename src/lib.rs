@@ -1,5 +1,5 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
 
 //use core::sync::atomic::{AtomicUsize, Ordering};
 //use defmt_brtt as _; // global logger
@@ -10,6 +10,8 @@
 use cortex_m::{self, delay::Delay};
 pub use stm32l4::stm32l4x6 as pac;
 
+mod access;
+
 pub mod gpio;
 pub mod timer;
 pub mod rcc;
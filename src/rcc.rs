@@ -1,25 +1,226 @@
-use crate::pac::{RCC, PWR, FLASH};
+use crate::access;
+use crate::pac;
 use core::marker::PhantomData;
 
+mod clocks;
+mod consumers;
+mod enable;
+mod kernel;
+
+pub use clocks::{Clocks, Kernel};
+pub use consumers::{Consumer, Consumers, Oscillator};
+pub use enable::{Enable, Reset};
+pub use kernel::{
+    AdcClock, Clk48Clock, DfsdmClock, I2cClock, KernelClock, LptimClock, SaiClock, SwpmiClock, UsartClock,
+};
+
+const HSI16_FREQ: u32 = 16_000_000;
+const HSI48_FREQ: u32 = 48_000_000;
+const LSE_FREQ: u32 = 32_768;
+const LSI_FREQ: u32 = 32_000;
+
+// Polls of a ready or status flag before giving up. Oscillators and the PLL
+// are ready well within this at any system clock.
+const TIMEOUT: u32 = 100_000;
+// A 32.768 kHz crystal can take up to 2 s to start
+const LSE_TIMEOUT: u32 = 5_000_000;
+
+// VCO limits for the main PLL and PLLSAI1/PLLSAI2
+const VCO_INPUT_MIN: u32 = 4_000_000;
+const VCO_INPUT_MAX: u32 = 16_000_000;
+const VCO_OUTPUT_MIN: u32 = 64_000_000;
+const VCO_OUTPUT_MAX: u32 = 344_000_000;
+const PLL_OUTPUT_MAX: u32 = 80_000_000;
+
+// Range 2 limits. HCLK and the PLL outputs share the 26 MHz limit.
+const RANGE2_MSI_MAX: u32 = 24_000_000;
+const RANGE2_HSE_MAX: u32 = 26_000_000;
+const RANGE2_CLOCK_MAX: u32 = 26_000_000;
+const RANGE2_VCO_MAX: u32 = 128_000_000;
+
+// HSE limits: a crystal must be 4 - 48 MHz, an external clock up to 48 MHz
+const HSE_CRYSTAL_MIN: u32 = 4_000_000;
+const HSE_MAX: u32 = 48_000_000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+/// Errors returned by clock configuration.
+pub enum Error {
+    /// PLLM is outside 1 - 8.
+    InvalidPLLM(u8),
+    /// PLLN is outside 8 - 86.
+    InvalidPLLN(u8),
+    /// PLLP is outside 2 - 31.
+    InvalidPLLP(u8),
+    /// The VCO input (source / PLLM) is outside 4 - 16 MHz.
+    VcoInputOutOfRange(u32),
+    /// The VCO output (VCO input * PLLN) is outside 64 - 344 MHz.
+    VcoOutputOutOfRange(u32),
+    /// A PLL output is above 80 MHz.
+    PLLOutputTooHigh(u32),
+    /// The output doesn't exist on this PLL. PLLSAI2 has no PLLQ.
+    InvalidPLLOutput,
+    /// PLLSRC or PLLM would change under a running PLL. The main PLL, PLLSAI1
+    /// and PLLSAI2 share them.
+    PLLInputInUse,
+    /// The HSE frequency is outside 4 - 48 MHz for a crystal, or 48 MHz for bypass.
+    HseOutOfRange(u32),
+    /// The HSE is already running at the given frequency, which differs from the
+    /// one asked for.
+    HseFrequencyMismatch(u32),
+    /// A clock would be above the limit of the voltage range. See the table
+    /// above `VoltageRange`.
+    ClockOutOfRange(u32),
+    /// The voltage range isn't available. The L496 has no Range 1 boost mode.
+    InvalidRange,
+    /// The regulator didn't reach the new voltage range (`PWR_SR2.VOSF`) in time.
+    RegulatorNotReady,
+    /// An oscillator or PLL didn't report ready in time.
+    OscillatorNotReady,
+    /// The system clock switch status didn't follow the switch in time.
+    SwitchTimeout,
+    /// The oscillator can't be reconfigured or stopped while something runs from
+    /// it. `ClockManager::consumers` tells what.
+    OscillatorInUse,
+}
+
 // System Clock type states
 pub struct SourceHSI16;
 pub struct SourceMSI;
 pub struct SourceHSE;
+pub struct SourcePLL;
+
+/// System clock source typestates.
+pub trait SysClkSource {
+    /// `CFGR.SW` value selecting this source.
+    const SW: u8;
+    /// Oscillator running the system clock, or `None` for the PLL.
+    const OSCILLATOR: Option<Oscillator>;
+}
+
+impl SysClkSource for SourceMSI {
+    const SW: u8 = 0b00;
+    const OSCILLATOR: Option<Oscillator> = Some(Oscillator::Msi);
+}
+
+impl SysClkSource for SourceHSI16 {
+    const SW: u8 = 0b01;
+    const OSCILLATOR: Option<Oscillator> = Some(Oscillator::Hsi16);
+}
+
+impl SysClkSource for SourceHSE {
+    const SW: u8 = 0b10;
+    const OSCILLATOR: Option<Oscillator> = Some(Oscillator::Hse);
+}
+
+impl SysClkSource for SourcePLL {
+    const SW: u8 = 0b11;
+    const OSCILLATOR: Option<Oscillator> = None;
+}
+
+/// HSE clock input.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HseMode {
+    /// Crystal or ceramic resonator on OSC_IN/OSC_OUT.
+    Crystal,
+    /// External clock on OSC_IN, with the oscillator bypassed (`HSEBYP`).
+    Bypass,
+}
+
+/// LSE crystal drive strength (`LSEDRV`). A higher drive starts harder crystals,
+/// at the cost of more current.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LseDrive {
+    Low = 0b00,
+    MediumLow = 0b01,
+    MediumHigh = 0b10,
+    High = 0b11,
+}
+
+/// LSE clock input.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LseMode {
+    /// 32.768 kHz crystal on OSC32_IN/OSC32_OUT.
+    Crystal(LseDrive),
+    /// External 32.768 kHz clock on OSC32_IN, with the oscillator bypassed
+    /// (`LSEBYP`).
+    Bypass,
+}
+
+/// Clock to start on wakeup from Stop mode (`CFGR.STOPWUCK`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WakeupClock {
+    Msi,
+    Hsi16,
+}
+
+/// System clock sources that can also drive the main PLL.
+pub trait PLLSource {
+    /// `PLLCFGR.PLLSRC` value selecting this source.
+    const PLLSRC: u8;
+}
+
+impl PLLSource for SourceMSI {
+    const PLLSRC: u8 = 0b01;
+}
+
+impl PLLSource for SourceHSI16 {
+    const PLLSRC: u8 = 0b10;
+}
+
+impl PLLSource for SourceHSE {
+    const PLLSRC: u8 = 0b11;
+}
+
+/// Main PLL typestates.
+pub trait PLLState {
+    /// PLLR, PLLQ and PLLP outputs in Hz while the PLL is running.
+    fn outputs(&self) -> Option<(u32, Option<u32>, Option<u32>)>;
+
+    /// VCO frequency in Hz while the PLL is running.
+    fn vco(&self) -> Option<u32>;
+}
 
 pub struct PLLDisabled;
-pub struct PLLEnabled;
+
+impl PLLState for PLLDisabled {
+    fn outputs(&self) -> Option<(u32, Option<u32>, Option<u32>)> {
+        None
+    }
+
+    fn vco(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// The main PLL is running with `config`, from an `input` Hz source.
+pub struct PLLEnabled {
+    config: PLLConfig,
+    input: u32,
+}
+
+impl PLLState for PLLEnabled {
+    fn outputs(&self) -> Option<(u32, Option<u32>, Option<u32>)> {
+        let input = self.input;
+        Some((self.config.r_clock(input), self.config.q_clock(input), self.config.p_clock(input)))
+    }
+
+    fn vco(&self) -> Option<u32> {
+        Some(self.config.vco(self.input))
+    }
+}
 
 // Voltage Range
 // Range   |  MSI  | HSI16 |  HSE  | PLL/PLLSAI1/PLLSAI2
 // Range 1 | 48MHz | 16MHz | 48MHz | 80MHz
 // Range 2 | 24MHz | 16MHz | 26MHz | 26MHz
-#[derive(PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum VoltageRange {
     VRange1Boost = 0b00,
     VRange1 = 0b01,
     VRange2 = 0b10,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum FlashLatency {
     Latency0 = 0b000,
     Latency1 = 0b001,
@@ -45,21 +246,432 @@ pub enum MSIRange {
     Range11 = 0b1011,    // 48MHz
 }
 
-// PLL Configure
+impl MSIRange {
+    /// MSI frequency in Hz.
+    pub const fn freq(&self) -> u32 {
+        match self {
+            Self::Range0 => 100_000,
+            Self::Range1 => 200_000,
+            Self::Range2 => 400_000,
+            Self::Range3 => 800_000,
+            Self::Range4 => 1_000_000,
+            Self::Range5 => 2_000_000,
+            Self::Range6 => 4_000_000,
+            Self::Range7 => 8_000_000,
+            Self::Range8 => 16_000_000,
+            Self::Range9 => 24_000_000,
+            Self::Range10 => 32_000_000,
+            Self::Range11 => 48_000_000,
+        }
+    }
+}
+
+/// AHB prescaler (`HPRE`), dividing SYSCLK into HCLK.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AHBPrescaler {
+    Div1 = 0b0000,
+    Div2 = 0b1000,
+    Div4 = 0b1001,
+    Div8 = 0b1010,
+    Div16 = 0b1011,
+    Div64 = 0b1100,
+    Div128 = 0b1101,
+    Div256 = 0b1110,
+    Div512 = 0b1111,
+}
+
+impl AHBPrescaler {
+    pub const fn divisor(&self) -> u32 {
+        match self {
+            Self::Div1 => 1,
+            Self::Div2 => 2,
+            Self::Div4 => 4,
+            Self::Div8 => 8,
+            Self::Div16 => 16,
+            Self::Div64 => 64,
+            Self::Div128 => 128,
+            Self::Div256 => 256,
+            Self::Div512 => 512,
+        }
+    }
+}
+
+/// APB prescaler (`PPRE1`, `PPRE2`), dividing HCLK into PCLK1 or PCLK2.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum APBPrescaler {
+    Div1 = 0b000,
+    Div2 = 0b100,
+    Div4 = 0b101,
+    Div8 = 0b110,
+    Div16 = 0b111,
+}
+
+impl APBPrescaler {
+    pub const fn divisor(&self) -> u32 {
+        match self {
+            Self::Div1 => 1,
+            Self::Div2 => 2,
+            Self::Div4 => 4,
+            Self::Div8 => 8,
+            Self::Div16 => 16,
+        }
+    }
+
+    /// Timer clock on this bus for a `pclk` Hz bus clock. The timers run at twice
+    /// PCLK whenever the bus is divided.
+    pub const fn timer_clock(&self, pclk: u32) -> u32 {
+        match self {
+            Self::Div1 => pclk,
+            _ => pclk * 2,
+        }
+    }
+}
+
+/// PLLR and PLLQ output dividers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PLLDiv {
+    Div2 = 0b00,
+    Div4 = 0b01,
+    Div6 = 0b10,
+    Div8 = 0b11,
+}
+
+impl PLLDiv {
+    const ALL: [PLLDiv; 4] = [PLLDiv::Div2, PLLDiv::Div4, PLLDiv::Div6, PLLDiv::Div8];
+
+    pub const fn divisor(&self) -> u32 {
+        (*self as u32 + 1) * 2
+    }
+}
+
+/// Main PLL configuration.
+///
+/// `f(VCO) = f(input) / PLLM * PLLN`, and each output divides the VCO: PLLR
+/// drives the system clock, PLLQ the 48 MHz clock and PLLP the SAI clocks.
+/// PLLR is always enabled; PLLQ and PLLP only when set. The VCO limits depend on
+/// the input frequency, so they're checked by `validate` and `enable_pll`.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct PLLConfig {
-    PLLPDIV: u8,
-    PLLR: u8,
-    PLLQ: u8,
-    PLLQEN: bool,
-    PLLP: bool,
-    PLLPEN: bool,
-    PLLN: u8,
-    PLLM: u8,
+    pllm: u8,
+    plln: u8,
+    pllr: PLLDiv,
+    pllq: Option<PLLDiv>,
+    pllp: Option<u8>,
+}
+
+impl PLLConfig {
+    /// PLLM 1 - 8, PLLN 8 - 86.
+    pub const fn new(pllm: u8, plln: u8, pllr: PLLDiv) -> Result<Self, Error> {
+        if let Err(error) = check_dividers(pllm, plln) {
+            return Err(error);
+        }
+
+        Ok(Self { pllm, plln, pllr, pllq: None, pllp: None })
+    }
+
+    /// The input divider, shared with PLLSAI1 and PLLSAI2.
+    pub const fn pllm(&self) -> u8 {
+        self.pllm
+    }
+
+    /// Enable the PLLQ output.
+    pub const fn pllq(mut self, div: PLLDiv) -> Self {
+        self.pllq = Some(div);
+        self
+    }
+
+    /// Enable the PLLP output, divided by 2 - 31.
+    pub const fn pllp(mut self, div: u8) -> Result<Self, Error> {
+        if let Err(error) = check_pllp(div) {
+            return Err(error);
+        }
+
+        self.pllp = Some(div);
+        Ok(self)
+    }
+
+    /// Check the VCO and output limits for an `input` Hz source.
+    pub fn validate(&self, input: u32) -> Result<(), Error> {
+        let outputs = [Some(self.r_clock(input)), self.q_clock(input), self.p_clock(input)];
+        check_pll(input, self.pllm, self.vco(input), outputs)
+    }
+
+    /// VCO frequency for an `input` Hz source.
+    pub fn vco(&self, input: u32) -> u32 {
+        (input as u64 * self.plln as u64 / self.pllm as u64) as u32
+    }
+
+    /// PLLR (system clock) output for an `input` Hz source.
+    pub fn r_clock(&self, input: u32) -> u32 {
+        self.vco(input) / self.pllr.divisor()
+    }
+
+    /// PLLQ (48 MHz clock) output for an `input` Hz source, if enabled.
+    pub fn q_clock(&self, input: u32) -> Option<u32> {
+        self.pllq.map(|div| self.vco(input) / div.divisor())
+    }
+
+    /// PLLP (SAI clock) output for an `input` Hz source, if enabled.
+    pub fn p_clock(&self, input: u32) -> Option<u32> {
+        self.pllp.map(|div| self.vco(input) / div as u32)
+    }
+}
+
+/// A PLL configuration found by `solve_pll`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PLLSolution {
+    pub config: PLLConfig,
+    /// PLLR output in Hz.
+    pub sys_clock: u32,
+    /// Difference between `sys_clock` and the target in Hz.
+    pub error: u32,
+}
+
+/// Search the PLL dividers for the system clock closest to `target` Hz from an
+/// `input` Hz source. With `usb_48mhz` set, PLLQ must also give exactly 48 MHz
+/// for USB, SDMMC and RNG. Ties go to the lowest PLLM and PLLN, which keeps the
+/// VCO input high and the VCO output low.
+///
+/// Returns `None` if no legal configuration exists for `input`.
+pub fn solve_pll(input: u32, target: u32, usb_48mhz: bool) -> Option<PLLSolution> {
+    let mut best: Option<PLLSolution> = None;
+    for pllm in 1..=8 {
+        for plln in 8..=86 {
+            let Ok(base) = PLLConfig::new(pllm, plln, PLLDiv::Div2) else {
+                continue;
+            };
+
+            let pllq = if usb_48mhz {
+                let vco = base.vco(input);
+                match PLLDiv::ALL.iter().find(|div| vco == 48_000_000 * div.divisor()) {
+                    Some(div) => Some(*div),
+                    None => continue,
+                }
+            } else {
+                None
+            };
+
+            for pllr in PLLDiv::ALL {
+                let config = PLLConfig { pllr, pllq, ..base };
+                if config.validate(input).is_err() {
+                    continue;
+                }
+
+                let sys_clock = config.r_clock(input);
+                let error = sys_clock.abs_diff(target);
+                if best.is_none_or(|best| error < best.error) {
+                    best = Some(PLLSolution { config, sys_clock, error });
+                }
+            }
+        }
+    }
+    best
+}
+
+/// PLLSAI1/PLLSAI2 configuration.
+///
+/// Both run from the main PLL's input and PLLM divider, so `pllm` must match the
+/// main PLL's while that runs, and the other PLLSAI's. PLLP drives the SAI
+/// clocks, PLLQ the 48 MHz clock (PLLSAI1 only) and PLLR the ADC clock. Each
+/// output is only enabled when set.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PLLSAIConfig {
+    pllm: u8,
+    plln: u8,
+    pllp: Option<u8>,
+    pllq: Option<PLLDiv>,
+    pllr: Option<PLLDiv>,
+}
+
+impl PLLSAIConfig {
+    /// PLLM 1 - 8, PLLN 8 - 86.
+    pub const fn new(pllm: u8, plln: u8) -> Result<Self, Error> {
+        if let Err(error) = check_dividers(pllm, plln) {
+            return Err(error);
+        }
+
+        Ok(Self { pllm, plln, pllp: None, pllq: None, pllr: None })
+    }
+
+    /// Enable the PLLP output, divided by 2 - 31.
+    pub const fn pllp(mut self, div: u8) -> Result<Self, Error> {
+        if let Err(error) = check_pllp(div) {
+            return Err(error);
+        }
+
+        self.pllp = Some(div);
+        Ok(self)
+    }
+
+    /// Enable the PLLQ output.
+    pub const fn pllq(mut self, div: PLLDiv) -> Self {
+        self.pllq = Some(div);
+        self
+    }
+
+    /// Enable the PLLR output.
+    pub const fn pllr(mut self, div: PLLDiv) -> Self {
+        self.pllr = Some(div);
+        self
+    }
+
+    pub const fn pllm(&self) -> u8 {
+        self.pllm
+    }
+
+    /// Check the VCO and output limits for an `input` Hz source.
+    pub fn validate(&self, input: u32) -> Result<(), Error> {
+        check_pll(input, self.pllm, self.vco(input), self.outputs(input))
+    }
+
+    /// VCO frequency for an `input` Hz source.
+    pub fn vco(&self, input: u32) -> u32 {
+        (input as u64 * self.plln as u64 / self.pllm as u64) as u32
+    }
+
+    /// PLLP (SAI clock) output for an `input` Hz source, if enabled.
+    pub fn p_clock(&self, input: u32) -> Option<u32> {
+        self.pllp.map(|div| self.vco(input) / div as u32)
+    }
+
+    /// PLLQ (48 MHz clock) output for an `input` Hz source, if enabled.
+    pub fn q_clock(&self, input: u32) -> Option<u32> {
+        self.pllq.map(|div| self.vco(input) / div.divisor())
+    }
+
+    /// PLLR (ADC clock) output for an `input` Hz source, if enabled.
+    pub fn r_clock(&self, input: u32) -> Option<u32> {
+        self.pllr.map(|div| self.vco(input) / div.divisor())
+    }
+
+    // PLLP, PLLQ and PLLR outputs, in `PLLSAIOutput` order
+    fn outputs(&self, input: u32) -> [Option<u32>; 3] {
+        [self.p_clock(input), self.q_clock(input), self.r_clock(input)]
+    }
+}
+
+/// PLLSAI1/PLLSAI2 outputs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PLLSAIOutput {
+    /// The SAI clock.
+    P,
+    /// The 48 MHz clock, PLLSAI1 only.
+    Q,
+    /// The ADC clock.
+    R,
+}
+
+/// A PLLSAI1/PLLSAI2 configuration found by `solve_pllsai`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PLLSAISolution {
+    /// Configuration with only the solved output enabled.
+    pub config: PLLSAIConfig,
+    /// Output frequency in Hz.
+    pub freq: u32,
+    /// Difference between `freq` and the target in Hz.
+    pub error: u32,
+}
+
+/// Search the PLLSAI dividers for the `output` frequency closest to `target` Hz
+/// from an `input` Hz source, e.g. a multiple of an audio sample rate for the
+/// SAI. Pass the PLLM of the running main PLL or PLLSAI as `pllm`, since they
+/// share it; with `None` every PLLM is tried. Ties go to the lowest PLLM, PLLN
+/// and divider.
+///
+/// Returns `None` if no legal configuration exists for `input`.
+pub fn solve_pllsai(input: u32, pllm: Option<u8>, output: PLLSAIOutput, target: u32) -> Option<PLLSAISolution> {
+    let pllms = match pllm {
+        Some(pllm) => pllm..=pllm,
+        None => 1..=8,
+    };
+
+    let mut best: Option<PLLSAISolution> = None;
+    for pllm in pllms {
+        for plln in 8..=86 {
+            let Ok(base) = PLLSAIConfig::new(pllm, plln) else {
+                continue;
+            };
+
+            // PLLP divides by 2 - 31, PLLQ and PLLR only by 2, 4, 6 and 8
+            for div in 2..=31 {
+                let pll_div = PLLDiv::ALL.into_iter().find(|pll_div| pll_div.divisor() == div as u32);
+                let config = match output {
+                    PLLSAIOutput::P => base.pllp(div).ok(),
+                    PLLSAIOutput::Q => pll_div.map(|div| base.pllq(div)),
+                    PLLSAIOutput::R => pll_div.map(|div| base.pllr(div)),
+                };
+                let Some(config) = config.filter(|config| config.validate(input).is_ok()) else {
+                    continue;
+                };
+
+                let freq = config.outputs(input)[output as usize].unwrap_or(0);
+                let error = freq.abs_diff(target);
+                if best.is_none_or(|best| error < best.error) {
+                    best = Some(PLLSAISolution { config, freq, error });
+                }
+            }
+        }
+    }
+    best
+}
+
+// PLLM and PLLN ranges, the same for every PLL
+const fn check_dividers(pllm: u8, plln: u8) -> Result<(), Error> {
+    if pllm < 1 || pllm > 8 {
+        return Err(Error::InvalidPLLM(pllm));
+    }
+    if plln < 8 || plln > 86 {
+        return Err(Error::InvalidPLLN(plln));
+    }
+    Ok(())
+}
+
+const fn check_pllp(div: u8) -> Result<(), Error> {
+    if div < 2 || div > 31 {
+        return Err(Error::InvalidPLLP(div));
+    }
+    Ok(())
+}
+
+// VCO and output limits of a PLL running from an `input` Hz source
+fn check_pll(input: u32, pllm: u8, vco: u32, outputs: [Option<u32>; 3]) -> Result<(), Error> {
+    let vco_input = input / pllm as u32;
+    if !(VCO_INPUT_MIN..=VCO_INPUT_MAX).contains(&vco_input) {
+        return Err(Error::VcoInputOutOfRange(vco_input));
+    }
+
+    if !(VCO_OUTPUT_MIN..=VCO_OUTPUT_MAX).contains(&vco) {
+        return Err(Error::VcoOutputOutOfRange(vco));
+    }
+
+    for freq in outputs.into_iter().flatten() {
+        if freq > PLL_OUTPUT_MAX {
+            return Err(Error::PLLOutputTooHigh(freq));
+        }
+    }
+
+    Ok(())
+}
+
+// A running PLLSAI1/PLLSAI2 with its `input` Hz source
+#[derive(Copy, Clone)]
+struct PLLSAIEnabled {
+    config: PLLSAIConfig,
+    input: u32,
 }
 
 pub struct ClockManager<SOURCE, PLL> {
     pub sys_clock: u32,
     msi_range: MSIRange,
+    // Last HSE frequency set. The HSE may have been stopped since.
+    hse: Option<u32>,
+    // Stop mode wakeup clock, once selected
+    wakeup: Option<WakeupClock>,
+    pllsai1: Option<PLLSAIEnabled>,
+    pllsai2: Option<PLLSAIEnabled>,
+    hpre: AHBPrescaler,
+    ppre1: APBPrescaler,
+    ppre2: APBPrescaler,
     source: SOURCE,
     pllenabled: PLL,
     //vrange: PhantomData<VRANGE>,
@@ -73,7 +685,14 @@ impl ClockManager<SourceMSI, PLLDisabled> {
 
         ClockManager { 
             sys_clock: 4_000_000, 
-            msi_range: MSIRange::Range4,  
+            msi_range: MSIRange::Range6,  
+            hse: None,
+            wakeup: None,
+            pllsai1: None,
+            pllsai2: None,
+            hpre: AHBPrescaler::Div1,
+            ppre1: APBPrescaler::Div1,
+            ppre2: APBPrescaler::Div1,
             source: SourceMSI, 
             pllenabled: PLLDisabled 
         }
@@ -88,128 +707,2029 @@ impl ClockManager<SourceMSI, PLLDisabled> {
     }
 }
 
+// The MSI range can't change under a PLL that runs from the MSI, checked at run
+// time since the PLLs may run from another oscillator
 impl<PLL> ClockManager<SourceMSI, PLL> {
-    pub fn update_msi_range(&mut self, new_range: MSIRange) {
-        let rcc = unsafe { &(*RCC::ptr()) };
+    pub fn update_msi_range(&mut self, new_range: MSIRange) -> Result<(), Error> {
+        let rcc = access::rcc();
         // NOTE: MSIRANGE can only be modified when MSI is OFF or when MSI is ready
         // Not when MSI is ON but not ready
 
-        // Confirm that MSI is OFF or (MSI is ON and MSI is RDY)
-        let msion = rcc.cr().read().msion().bit();
-        let msirdy = rcc.cr().read().msirdy().bit();
-        if msion && !msirdy {
-            // Can't change MSI range right now
-            // TODO: Figure out what to do here...
-            // Maybe return a Result here
+        // The main PLL or PLLSAI1/PLLSAI2 may run from the MSI
+        if consumers::consumers(Oscillator::Msi).contains(Consumer::PllInput) {
+            return Err(Error::OscillatorInUse);
         }
 
-        // Need to update the flash wait states
-        // Enable the PWR clock if not already
-        if rcc.apb1enr1().read().pwren().bit_is_clear() {
-            rcc.apb1enr1().modify(|_,w| w.pwren().set_bit());
-            // Need a delay after enabling 
-            while rcc.apb1enr1().read().pwren().bit_is_clear() {
+        // The MSI runs SYSCLK here, so it is on. Give it time to settle.
+        wait_for(|| rcc.cr().read().msirdy().bit_is_set(), Error::OscillatorNotReady)?;
+
+        // Only once the change is going ahead, so a refused one leaves VOS alone
+        raise_range(new_range.freq() <= RANGE2_MSI_MAX)?;
 
+        let to = new_range.freq() / self.hpre.divisor();
+        let bits = new_range.clone() as u8;
+        let old_bits = rcc.cr().read().msirange().bits();
+        // MSIRGSEL is write-only in the PAC
+        let old_sel = rcc.cr().read().bits() & (1 << 3) != 0;
+        change_hclk(self.hclk(), to, || {
+            // Set the MSIRGSEL to set range based on the CR value
+            rcc.cr().modify(|_,w| w.msirgsel().set_bit());
+
+            // Update the MSI range
+            rcc.cr().modify(|_,w| unsafe { w.msirange().bits(bits) });
+
+            let result = wait_for(|| rcc.cr().read().msirdy().bit_is_set(), Error::OscillatorNotReady);
+            if result.is_err() {
+                rcc.cr().modify(|_,w| unsafe { w.msirange().bits(old_bits).msirgsel().bit(old_sel) });
             }
+            result
+        })?;
+
+        self.sys_clock = new_range.freq();
+        self.msi_range = new_range;
+        Ok(())
+    }
+
+}
+
+impl<SOURCE, PLL> ClockManager<SOURCE, PLL> {
+    /// Set the AHB prescaler. HCLK must stay within the voltage range limit; the
+    /// flash wait states follow the new HCLK.
+    pub fn ahb_prescaler(mut self, hpre: AHBPrescaler) -> Result<Self, Error> {
+        let rcc = access::rcc();
+        let bits = hpre as u8;
+        change_hclk(self.hclk(), self.sys_clock / hpre.divisor(), || {
+            rcc.cfgr().modify(|_,w| unsafe { w.hpre().bits(bits) });
+            // The new prescaler takes a few cycles to apply
+            wait_for(|| rcc.cfgr().read().hpre().bits() == bits, Error::SwitchTimeout)
+        })?;
+
+        self.hpre = hpre;
+        Ok(self)
+    }
+
+    /// Set the APB1 prescaler. PCLK1 is at most HCLK, so unlike the AHB
+    /// prescaler this can't take a clock out of the voltage range limit.
+    pub fn apb1_prescaler(mut self, ppre1: APBPrescaler) -> Self {
+        let rcc = access::rcc();
+        rcc.cfgr().modify(|_,w| unsafe { w.ppre1().bits(ppre1 as u8) });
+
+        self.ppre1 = ppre1;
+        self
+    }
+
+    /// Set the APB2 prescaler. PCLK2 is at most HCLK, so unlike the AHB
+    /// prescaler this can't take a clock out of the voltage range limit.
+    pub fn apb2_prescaler(mut self, ppre2: APBPrescaler) -> Self {
+        let rcc = access::rcc();
+        rcc.cfgr().modify(|_,w| unsafe { w.ppre2().bits(ppre2 as u8) });
+
+        self.ppre2 = ppre2;
+        self
+    }
+
+    /// Start the LSE. It lives in the backup domain and keeps running through
+    /// resets, so an LSE that is already on is used as is.
+    pub fn enable_lse(self, mode: LseMode) -> Result<Self, Error> {
+        backup_domain_access();
+        let rcc = access::rcc();
+
+        let started = rcc.bdcr().read().lseon().bit_is_clear();
+        if started {
+            // LSEBYP can only be written while the LSE is off
+            rcc.bdcr().modify(|_,w| match mode {
+                LseMode::Crystal(drive) => unsafe { w.lsebyp().clear_bit().lsedrv().bits(drive as u8) },
+                LseMode::Bypass => w.lsebyp().set_bit(),
+            });
+            rcc.bdcr().modify(|_,w| w.lseon().set_bit());
         }
-        let pwr = unsafe { &(*PWR::ptr()) };
-        let curr_vos;
-        if pwr.cr1().read().vos() == VoltageRange::VRange2 as u8 {
-            curr_vos = VoltageRange::VRange2;
-        } else {
-            curr_vos = VoltageRange::VRange1;
-        }
-        // L496 doesn't have boost range
-        // Set flash states based on the MSI range
-        let new_latency: FlashLatency;
-        if curr_vos == VoltageRange::VRange1 {
-            match new_range {
-                MSIRange::Range0 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range1 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range2 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range3 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range4 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range5 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range6 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range7 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range8 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range9 => new_latency = FlashLatency::Latency1,
-                MSIRange::Range10 => new_latency = FlashLatency::Latency1,
-                MSIRange::Range11 => new_latency = FlashLatency::Latency2,
-            };
-        } else {
-            match new_range {
-                MSIRange::Range0 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range1 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range2 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range3 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range4 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range5 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range6 => new_latency = FlashLatency::Latency0,
-                MSIRange::Range7 => new_latency = FlashLatency::Latency1,
-                MSIRange::Range8 => new_latency = FlashLatency::Latency2,
-                MSIRange::Range9 => new_latency = FlashLatency::Latency2,
-                MSIRange::Range10 => new_latency = FlashLatency::Latency2,
-                MSIRange::Range11 => new_latency = FlashLatency::Latency2,
-            };
-        }
-        let flash = unsafe { &(*FLASH::ptr()) };
-        flash.acr().modify(|_,w| unsafe { w.latency().bits(new_latency as u8) });
 
-        self.msi_range = new_range.clone();
+        let ready = || rcc.bdcr().read().lserdy().bit_is_set();
+        if let Err(error) = wait_polls(LSE_TIMEOUT, ready, Error::OscillatorNotReady) {
+            if started {
+                rcc.bdcr().modify(|_,w| w.lseon().clear_bit());
+            }
+            return Err(error);
+        }
+        Ok(self)
+    }
 
-        // Set the MSIRGSEL to set range based on the CR value
-        rcc.cr().modify(|_,w| w.msirgsel().set_bit());
+    /// Enable the LSE clock security system, starting the LSI it runs from if
+    /// needed. Only a backup domain reset turns it off again. When the LSE
+    /// fails, `LSECSSD` is set and `Clocks::lse` reports it as stopped.
+    pub fn enable_lse_css(self) -> Result<Self, Error> {
+        let rcc = access::rcc();
+        if rcc.bdcr().read().lserdy().bit_is_clear() {
+            return Err(Error::OscillatorNotReady);
+        }
 
-        // Update the MSI range
-        rcc.cr().modify(|_,w| unsafe { w.msirange().bits(new_range as u8) });
+        let result = self.enable_lsi()?;
+        backup_domain_access();
+        rcc.bdcr().modify(|_,w| w.lsecsson().set_bit());
+        Ok(result)
+    }
 
-        while rcc.cr().read().msirdy().bit_is_clear() {
+    /// Start the 32 kHz LSI.
+    pub fn enable_lsi(self) -> Result<Self, Error> {
+        start_lsi()?;
+        Ok(self)
+    }
 
+    /// Trim the MSI continuously against the LSE (`MSIPLLEN`). Enabling it needs
+    /// a running LSE that the clock security system hasn't flagged.
+    pub fn msi_pll_mode(self, enable: bool) -> Result<Self, Error> {
+        let rcc = access::rcc();
+        let bdcr = rcc.bdcr().read();
+        if enable && (bdcr.lserdy().bit_is_clear() || bdcr.lsecssd().bit_is_set()) {
+            return Err(Error::OscillatorNotReady);
         }
+
+        rcc.cr().modify(|_,w| w.msipllen().bit(enable));
+        Ok(self)
     }
 
-    // Function to enable the PLL with the given config values
-    // This probably can be a common function for all states
-    pub fn enable_pll(self) {
-        // Configure the PLL
+    /// Set the flash prefetch buffer (`PRFTEN`) and the instruction and data
+    /// caches (`ICEN`, `DCEN`). The caches are on out of reset, prefetch is off.
+    /// Clock changes only touch the wait states, so this setting is kept.
+    ///
+    /// A cache that was off is reset (`ICRST`, `DCRST`) before it is turned
+    /// back on, so it doesn't serve lines from before it was disabled.
+    pub fn flash_acceleration(self, prefetch: bool, caches: bool) -> Self {
+        let flash = access::flash();
+        let acr = flash.acr().read();
+        let reset_icache = caches && acr.icen().bit_is_clear();
+        let reset_dcache = caches && acr.dcen().bit_is_clear();
+        // The reset bits only work while the cache is disabled
+        if reset_icache || reset_dcache {
+            flash.acr().modify(|_,w| w.icrst().bit(reset_icache).dcrst().bit(reset_dcache));
+            flash.acr().modify(|_,w| w.icrst().clear_bit().dcrst().clear_bit());
+        }
 
+        flash.acr().modify(|_,w| w.prften().bit(prefetch).icen().bit(caches).dcen().bit(caches));
+        self
+    }
 
-        // Need to make sure the source clock is ready
+    /// Stop the LSE. Fails with `OscillatorInUse` while something runs from it.
+    pub fn disable_lse(self) -> Result<Self, Error> {
+        if !self.consumers(Oscillator::Lse).is_empty() {
+            return Err(Error::OscillatorInUse);
+        }
+        stop(Oscillator::Lse);
+        Ok(self)
+    }
 
-        // Enable the PLL 
+    /// Stop the LSI. Fails with `OscillatorInUse` while something runs from it.
+    pub fn disable_lsi(self) -> Result<Self, Error> {
+        if !self.consumers(Oscillator::Lsi).is_empty() {
+            return Err(Error::OscillatorInUse);
+        }
+        stop(Oscillator::Lsi);
+        Ok(self)
     }
 
-    pub fn switch_to_hsi(self) -> ClockManager<SourceHSI16, PLL> {
-        let rcc = unsafe { &(*RCC::ptr()) };
+    /// Select the kernel clock of a peripheral. The HSI16, HSI48 and LSI are
+    /// started when selected; the LSE and MSI must already be running, else this
+    /// fails with `OscillatorNotReady`. The oscillator of the previous selection
+    /// is turned off once nothing else runs from it.
+    ///
+    /// PLL outputs aren't checked, `Clocks::kernel` reports `None` for one that
+    /// isn't enabled.
+    pub fn kernel_clock(self, selection: KernelClock) -> Result<Self, Error> {
+        let rcc = access::rcc();
+        match selection.oscillator() {
+            Some(Oscillator::Hsi16) => {
+                start_hsi()?;
+            }
+            Some(Oscillator::Hsi48) => {
+                start_hsi48()?;
+            }
+            Some(Oscillator::Lsi) => {
+                start_lsi()?;
+            }
+            Some(Oscillator::Lse) => {
+                let bdcr = rcc.bdcr().read();
+                if bdcr.lserdy().bit_is_clear() || bdcr.lsecssd().bit_is_set() {
+                    return Err(Error::OscillatorNotReady);
+                }
+            }
+            Some(Oscillator::Msi) if rcc.cr().read().msirdy().bit_is_clear() => {
+                return Err(Error::OscillatorNotReady);
+            }
+            _ => {}
+        }
+
+        let old = kernel::current(selection.kernel());
+        kernel::select(selection);
+        self.release(old.and_then(|old| old.oscillator()));
+        Ok(self)
+    }
 
-        // First turn on the HSI16
-        rcc.cr().modify(|_,w| w.hsion().set_bit());
+    /// Select the clock the system restarts on after Stop mode. The selected
+    /// oscillator is then kept on.
+    pub fn stop_wakeup_clock(mut self, clock: WakeupClock) -> Result<Self, Error> {
+        let rcc = access::rcc();
+        rcc.cfgr().modify(|_,w| w.stopwuck().bit(clock == WakeupClock::Hsi16));
 
-        // Wait for HSI ready
-        while rcc.cr().read().hsirdy().bit_is_clear() {
-            // TODO: Consider adding a timeout here
+        let old = self.wakeup.replace(clock);
+        match old {
+            Some(WakeupClock::Msi) => self.release(Some(Oscillator::Msi)),
+            Some(WakeupClock::Hsi16) => self.release(Some(Oscillator::Hsi16)),
+            None => {}
         }
+        Ok(self)
+    }
 
-        // Switch to the HSI
-        rcc.cfgr().modify( unsafe { |_,w| w.sw().bits(0b01) } );
+    /// What keeps `oscillator` on. It is turned off when the last user goes.
+    pub fn consumers(&self, oscillator: Oscillator) -> Consumers {
+        let mut consumers = consumers::consumers(oscillator);
+        let wakeup = match self.wakeup {
+            Some(WakeupClock::Msi) => Some(Oscillator::Msi),
+            Some(WakeupClock::Hsi16) => Some(Oscillator::Hsi16),
+            None => None,
+        };
+        if wakeup == Some(oscillator) {
+            consumers.add(Consumer::Wakeup);
+        }
+        consumers
+    }
 
-        // Wait for system clock switch status
-        while rcc.cfgr().read().sws().bits() != 0b01 {
-            // TODO: Consider adding a timeout here
+    // Turn off `oscillator` after one of its users let go of it, if that was the
+    // last one
+    fn release(&self, oscillator: Option<Oscillator>) {
+        if let Some(oscillator) = oscillator.filter(|oscillator| self.consumers(*oscillator).is_empty()) {
+            stop(oscillator);
         }
+    }
 
-        // Turn off the MSI if not used
-        // TODO: Put the clock sources in a counting ref or something
-        rcc.cr().modify(|_,w| w.msion().clear_bit());
+    fn hclk(&self) -> u32 {
+        self.sys_clock / self.hpre.divisor()
+    }
 
-        let result = ClockManager {
-            sys_clock: 16_000_000,
+    // Move to a new source or PLL state, keeping the rest of the configuration
+    fn into_state<S, P, F>(self, sys_clock: u32, state: F) -> ClockManager<S, P>
+    where
+        F: FnOnce(SOURCE, PLL) -> (S, P),
+    {
+        let (source, pllenabled) = state(self.source, self.pllenabled);
+        ClockManager {
+            sys_clock,
             msi_range: self.msi_range,
-            // vrange: self.vrange,
-            source: SourceHSI16,
-            pllenabled: self.pllenabled,
+            hse: self.hse,
+            wakeup: self.wakeup,
+            pllsai1: self.pllsai1,
+            pllsai2: self.pllsai2,
+            hpre: self.hpre,
+            ppre1: self.ppre1,
+            ppre2: self.ppre2,
+            source,
+            pllenabled,
+        }
+    }
+}
+
+impl<SOURCE, PLL: PLLState> ClockManager<SOURCE, PLL> {
+    /// Switch the core voltage range. Range 2 saves power but limits the clocks,
+    /// see the table above `VoltageRange`. Switching to it fails with
+    /// `ClockOutOfRange` while a running clock is above its Range 2 limit.
+    ///
+    /// Clock changes that need Range 1 switch back to it on their own.
+    pub fn voltage_range(self, range: VoltageRange) -> Result<Self, Error> {
+        let current = current_range();
+        match range {
+            VoltageRange::VRange1Boost => return Err(Error::InvalidRange),
+            _ if range == current => {}
+            VoltageRange::VRange2 => {
+                self.check_range2()?;
+                // Range 2 needs more wait states at the same HCLK
+                let flash = access::flash();
+                let old_acr = flash.acr().read().bits();
+                set_flash_latency(flash_latency(self.hclk(), VoltageRange::VRange2));
+                if let Err(error) = set_vos(VoltageRange::VRange2) {
+                    flash.acr().write(|w| unsafe { w.bits(old_acr) });
+                    return Err(error);
+                }
+            }
+            VoltageRange::VRange1 => {
+                set_vos(VoltageRange::VRange1)?;
+                set_flash_latency(flash_latency(self.hclk(), VoltageRange::VRange1));
+            }
+        }
+        Ok(self)
+    }
+
+    // Check the running clocks against the Range 2 limits
+    fn check_range2(&self) -> Result<(), Error> {
+        let rcc = access::rcc();
+        let cr = rcc.cr().read();
+
+        let mut clocks = [None; 15];
+        clocks[0] = Some((self.hclk(), RANGE2_CLOCK_MAX));
+        clocks[1] = cr.msion().bit().then_some((self.msi_range.freq(), RANGE2_MSI_MAX));
+        clocks[2] = self.hse.filter(|_| cr.hseon().bit()).map(|hse| (hse, RANGE2_HSE_MAX));
+        clocks[3] = self.pllenabled.vco().map(|vco| (vco, RANGE2_VCO_MAX));
+        if let Some((r, q, p)) = self.pllenabled.outputs() {
+            clocks[4] = Some((r, RANGE2_CLOCK_MAX));
+            clocks[5] = q.map(|q| (q, RANGE2_CLOCK_MAX));
+            clocks[6] = p.map(|p| (p, RANGE2_CLOCK_MAX));
+        }
+        // VCO and PLLP/PLLQ/PLLR of PLLSAI1 and PLLSAI2
+        for (pllsai, clocks) in [self.pllsai1, self.pllsai2].into_iter().zip(clocks[7..].chunks_mut(4)) {
+            if let Some(PLLSAIEnabled { config, input }) = pllsai {
+                clocks[0] = Some((config.vco(input), RANGE2_VCO_MAX));
+                for (clock, freq) in clocks[1..].iter_mut().zip(config.outputs(input)) {
+                    *clock = freq.map(|freq| (freq, RANGE2_CLOCK_MAX));
+                }
+            }
+        }
+
+        match clocks.into_iter().flatten().find(|(freq, max)| freq > max) {
+            Some((freq, _)) => Err(Error::ClockOutOfRange(freq)),
+            None => Ok(()),
+        }
+    }
+
+    /// Finish clock configuration and return the frequencies of the clock tree,
+    /// for the peripheral constructors.
+    pub fn freeze(self) -> Clocks {
+        let rcc = access::rcc();
+        let cr = rcc.cr().read();
+        let bdcr = rcc.bdcr().read();
+        let pll = self.pllenabled.outputs();
+        let hclk = self.hclk();
+        let pclk1 = hclk / self.ppre1.divisor();
+        let pclk2 = hclk / self.ppre2.divisor();
+
+        let mut clocks = Clocks {
+            sysclk: self.sys_clock,
+            hclk,
+            pclk1,
+            pclk2,
+            timclk1: self.ppre1.timer_clock(pclk1),
+            timclk2: self.ppre2.timer_clock(pclk2),
+            msi: cr.msirdy().bit().then_some(self.msi_range.freq()),
+            hsi16: cr.hsirdy().bit().then_some(HSI16_FREQ),
+            hsi48: rcc.crrcr().read().hsi48rdy().bit().then_some(HSI48_FREQ),
+            hse: self.hse.filter(|_| cr.hserdy().bit()),
+            lse: (bdcr.lserdy().bit() && !bdcr.lsecssd().bit()).then_some(LSE_FREQ),
+            lsi: rcc.csr().read().lsirdy().bit().then_some(LSI_FREQ),
+            pllclk: pll.map(|(r, _, _)| r),
+            pllq: pll.and_then(|(_, q, _)| q),
+            pllp: pll.and_then(|(_, _, p)| p),
+            pllsai1: self.pllsai1.map_or([None; 3], |pllsai| pllsai.config.outputs(pllsai.input)),
+            pllsai2: self.pllsai2.map_or([None; 3], |pllsai| pllsai.config.outputs(pllsai.input)),
+            msi_pll_mode: cr.msipllen().bit(),
+            kernel: [None; Kernel::COUNT],
         };
-        result
+        let (ccipr, ccipr2) = kernel::registers();
+        clocks.select_kernels(ccipr, ccipr2);
+        clocks
     }
-}
\ No newline at end of file
+}
+
+// `switch_to_msi` for every source other than the MSI itself
+macro_rules! switch_to_msi {
+    ($($source:ty),+) => {
+        $(
+            impl<PLL> ClockManager<$source, PLL> {
+                /// Start the MSI in `range` and run the system clock from it. The
+                /// previous oscillator is turned off unless something else runs
+                /// from it.
+                ///
+                /// If the PLL runs from the MSI, its range can't change and
+                /// `range` must be the current one.
+                pub fn switch_to_msi(self, range: MSIRange) -> Result<ClockManager<SourceMSI, PLL>, Error> {
+                    let freq = range.freq();
+                    let started = start_msi(&range)?;
+                    switch_source(SourceMSI::OSCILLATOR, started, self.hclk(), freq / self.hpre.divisor())?;
+
+                    let mut result = self.into_state(freq, |_, pll| (SourceMSI, pll));
+                    result.msi_range = range;
+                    result.release(<$source>::OSCILLATOR);
+                    Ok(result)
+                }
+            }
+        )+
+    };
+}
+
+switch_to_msi!(SourceHSI16, SourceHSE, SourcePLL);
+
+// `switch_to_hsi` for every source other than the HSI16 itself
+macro_rules! switch_to_hsi {
+    ($($source:ty),+) => {
+        $(
+            impl<PLL> ClockManager<$source, PLL> {
+                /// Run the system clock from the HSI16. The previous oscillator is
+                /// turned off unless something else runs from it.
+                pub fn switch_to_hsi(self) -> Result<ClockManager<SourceHSI16, PLL>, Error> {
+                    let started = start_hsi()?;
+                    switch_source(SourceHSI16::OSCILLATOR, started, self.hclk(), HSI16_FREQ / self.hpre.divisor())?;
+
+                    let result = self.into_state(HSI16_FREQ, |_, pll| (SourceHSI16, pll));
+                    result.release(<$source>::OSCILLATOR);
+                    Ok(result)
+                }
+            }
+        )+
+    };
+}
+
+switch_to_hsi!(SourceMSI, SourceHSE, SourcePLL);
+
+// `switch_to_hse` for every source other than the HSE itself
+macro_rules! switch_to_hse {
+    ($($source:ty),+) => {
+        $(
+            impl<PLL> ClockManager<$source, PLL> {
+                /// Start the HSE at `freq` Hz and run the system clock from it. The
+                /// previous oscillator is turned off unless something else runs
+                /// from it.
+                ///
+                /// If the HSE is already running as the PLL input it is used as is,
+                /// since `HSEBYP` can only change while the HSE is off, and `freq`
+                /// must match the frequency it was started at.
+                pub fn switch_to_hse(self, freq: u32, mode: HseMode) -> Result<ClockManager<SourceHSE, PLL>, Error> {
+                    let running = self.hse.filter(|_| access::rcc().cr().read().hseon().bit());
+                    if let Some(hse) = running.filter(|hse| *hse != freq) {
+                        return Err(Error::HseFrequencyMismatch(hse));
+                    }
+
+                    let started = start_hse(freq, mode)?;
+                    switch_source(SourceHSE::OSCILLATOR, started, self.hclk(), freq / self.hpre.divisor())?;
+
+                    let mut result = self.into_state(freq, |_, pll| (SourceHSE, pll));
+                    result.hse = Some(freq);
+                    result.release(<$source>::OSCILLATOR);
+                    Ok(result)
+                }
+            }
+        )+
+    };
+}
+
+switch_to_hse!(SourceMSI, SourceHSI16, SourcePLL);
+
+// Start the MSI in `range` if needed. Returns whether it was off.
+fn start_msi(range: &MSIRange) -> Result<bool, Error> {
+    raise_range(range.freq() <= RANGE2_MSI_MAX)?;
+
+    let rcc = access::rcc();
+    let bits = range.clone() as u8;
+    let cr = rcc.cr().read();
+    let started = cr.msion().bit_is_clear();
+    if !started {
+        let pll_input = consumers::consumers(Oscillator::Msi).contains(Consumer::PllInput);
+        if cr.msirange().bits() != bits && pll_input {
+            return Err(Error::OscillatorInUse);
+        }
+        // MSIRANGE can only change while the MSI is off or ready
+        wait_for(|| rcc.cr().read().msirdy().bit_is_set(), Error::OscillatorNotReady)?;
+    }
+
+    rcc.cr().modify(|_,w| unsafe { w.msirgsel().set_bit().msirange().bits(bits) });
+    rcc.cr().modify(|_,w| w.msion().set_bit());
+    if let Err(error) = wait_for(|| rcc.cr().read().msirdy().bit_is_set(), Error::OscillatorNotReady) {
+        if started {
+            rcc.cr().modify(|_,w| w.msion().clear_bit());
+        }
+        return Err(error);
+    }
+    Ok(started)
+}
+
+// Start the HSI16 if needed. Returns whether it was off. It runs in both voltage
+// ranges.
+fn start_hsi() -> Result<bool, Error> {
+    let rcc = access::rcc();
+    let started = rcc.cr().read().hsion().bit_is_clear();
+    rcc.cr().modify(|_,w| w.hsion().set_bit());
+
+    if let Err(error) = wait_for(|| rcc.cr().read().hsirdy().bit_is_set(), Error::OscillatorNotReady) {
+        if started {
+            rcc.cr().modify(|_,w| w.hsion().clear_bit());
+        }
+        return Err(error);
+    }
+    Ok(started)
+}
+
+// Start the HSI48 if needed. Returns whether it was off.
+fn start_hsi48() -> Result<bool, Error> {
+    let rcc = access::rcc();
+    let started = rcc.crrcr().read().hsi48on().bit_is_clear();
+    rcc.crrcr().modify(|_,w| w.hsi48on().set_bit());
+
+    if let Err(error) = wait_for(|| rcc.crrcr().read().hsi48rdy().bit_is_set(), Error::OscillatorNotReady) {
+        if started {
+            rcc.crrcr().modify(|_,w| w.hsi48on().clear_bit());
+        }
+        return Err(error);
+    }
+    Ok(started)
+}
+
+// Start the LSI if needed. Returns whether it was off.
+fn start_lsi() -> Result<bool, Error> {
+    let rcc = access::rcc();
+    let started = rcc.csr().read().lsion().bit_is_clear();
+    rcc.csr().modify(|_,w| w.lsion().set_bit());
+
+    if let Err(error) = wait_for(|| rcc.csr().read().lsirdy().bit_is_set(), Error::OscillatorNotReady) {
+        if started {
+            rcc.csr().modify(|_,w| w.lsion().clear_bit());
+        }
+        return Err(error);
+    }
+    Ok(started)
+}
+
+// Start the HSE at `freq` Hz if needed. Returns whether it was off.
+fn start_hse(freq: u32, mode: HseMode) -> Result<bool, Error> {
+    let min = match mode {
+        HseMode::Crystal => HSE_CRYSTAL_MIN,
+        HseMode::Bypass => 1,
+    };
+    if !(min..=HSE_MAX).contains(&freq) {
+        return Err(Error::HseOutOfRange(freq));
+    }
+    raise_range(freq <= RANGE2_HSE_MAX)?;
+
+    let rcc = access::rcc();
+    let started = rcc.cr().read().hseon().bit_is_clear();
+    if started {
+        // HSEBYP can only be written while the HSE is off
+        rcc.cr().modify(|_,w| w.hsebyp().bit(mode == HseMode::Bypass));
+        rcc.cr().modify(|_,w| w.hseon().set_bit());
+    }
+
+    if let Err(error) = wait_for(|| rcc.cr().read().hserdy().bit_is_set(), Error::OscillatorNotReady) {
+        if started {
+            rcc.cr().modify(|_,w| w.hseon().clear_bit());
+        }
+        return Err(error);
+    }
+    Ok(started)
+}
+
+// Move SYSCLK to `new`, taking HCLK from `from` to `to` Hz. On failure the new
+// oscillator is stopped again if it was `started` for this switch.
+fn switch_source(new: Option<Oscillator>, started: bool, from: u32, to: u32) -> Result<(), Error> {
+    let sw = match new {
+        Some(Oscillator::Msi) => SourceMSI::SW,
+        Some(Oscillator::Hsi16) => SourceHSI16::SW,
+        Some(Oscillator::Hse) => SourceHSE::SW,
+        _ => SourcePLL::SW,
+    };
+    if let Err(error) = switch_sysclk(sw, from, to) {
+        if let (true, Some(new)) = (started, new) {
+            stop(new);
+        }
+        return Err(error);
+    }
+    Ok(())
+}
+
+
+impl<SOURCE: PLLSource> ClockManager<SOURCE, PLLDisabled> {
+    /// Configure the main PLL from the current system clock source and start it.
+    /// The system clock stays on the source; use `switch_to_pll` to move it.
+    pub fn enable_pll(self, config: PLLConfig) -> Result<ClockManager<SOURCE, PLLEnabled>, Error> {
+        let input = self.sys_clock;
+        config.validate(input)?;
+
+        let outputs = [Some(config.r_clock(input)), config.q_clock(input), config.p_clock(input)];
+        raise_range(
+            config.vco(input) <= RANGE2_VCO_MAX
+                && outputs.into_iter().flatten().all(|freq| freq <= RANGE2_CLOCK_MAX),
+        )?;
+
+        let rcc = access::rcc();
+        let cr = rcc.cr().read();
+        if cr.pllsai1on().bit() || cr.pllsai2on().bit() {
+            let pllcfgr = rcc.pllcfgr().read();
+            if pllcfgr.pllsrc().bits() != SOURCE::PLLSRC || pllcfgr.pllm().bits() + 1 != config.pllm {
+                return Err(Error::PLLInputInUse);
+            }
+        }
+
+        // PLLCFGR can only be written while the PLL is off
+        rcc.cr().modify(|_,w| w.pllon().clear_bit());
+        wait_for(|| rcc.cr().read().pllrdy().bit_is_clear(), Error::OscillatorNotReady)?;
+
+        rcc.pllcfgr().write(|w| unsafe {
+            w.pllsrc().bits(SOURCE::PLLSRC);
+            w.pllm().bits(config.pllm - 1);
+            w.plln().bits(config.plln);
+            w.pllren().set_bit();
+            w.pllr().bits(config.pllr as u8);
+            if let Some(pllq) = config.pllq {
+                w.pllqen().set_bit();
+                w.pllq().bits(pllq as u8);
+            }
+            if let Some(pllp) = config.pllp {
+                w.pllpen().set_bit();
+                w.pllpdiv().bits(pllp);
+            }
+            w
+        });
+
+        rcc.cr().modify(|_,w| w.pllon().set_bit());
+        if let Err(error) = wait_for(|| rcc.cr().read().pllrdy().bit_is_set(), Error::OscillatorNotReady) {
+            rcc.cr().modify(|_,w| w.pllon().clear_bit());
+            return Err(error);
+        }
+
+        Ok(self.into_state(input, |source, _| (source, PLLEnabled { config, input })))
+    }
+}
+
+impl<SOURCE> ClockManager<SOURCE, PLLEnabled> {
+    pub fn pll_config(&self) -> PLLConfig {
+        self.pllenabled.config
+    }
+
+    /// PLLR output in Hz, the system clock when running from the PLL.
+    pub fn pll_clock(&self) -> u32 {
+        self.pllenabled.config.r_clock(self.pllenabled.input)
+    }
+
+    /// PLLQ output in Hz, if enabled.
+    pub fn pll_q_clock(&self) -> Option<u32> {
+        self.pllenabled.config.q_clock(self.pllenabled.input)
+    }
+
+    /// PLLP output in Hz, if enabled.
+    pub fn pll_p_clock(&self) -> Option<u32> {
+        self.pllenabled.config.p_clock(self.pllenabled.input)
+    }
+}
+
+impl<SOURCE: PLLSource> ClockManager<SOURCE, PLLEnabled> {
+    /// Run the system clock from the PLL. The PLL's source stays on.
+    pub fn switch_to_pll(self) -> Result<ClockManager<SourcePLL, PLLEnabled>, Error> {
+        let sys_clock = self.pll_clock();
+        switch_sysclk(SourcePLL::SW, self.hclk(), sys_clock / self.hpre.divisor())?;
+
+        Ok(self.into_state(sys_clock, |_, pll| (SourcePLL, pll)))
+    }
+
+    /// Stop the main PLL.
+    ///
+    /// The PLL input is turned off too, unless something else runs from it.
+    pub fn disable_pll(self) -> Result<ClockManager<SOURCE, PLLDisabled>, Error> {
+        let rcc = access::rcc();
+        rcc.cr().modify(|_,w| w.pllon().clear_bit());
+        wait_for(|| rcc.cr().read().pllrdy().bit_is_clear(), Error::OscillatorNotReady)?;
+        self.release(consumers::pll_input());
+
+        let sys_clock = self.sys_clock;
+        Ok(self.into_state(sys_clock, |source, _| (source, PLLDisabled)))
+    }
+}
+
+// PLLSAI1 and PLLSAI2
+#[derive(Copy, Clone, PartialEq)]
+enum PllSai {
+    Sai1,
+    Sai2,
+}
+
+impl<SOURCE: SysClkSource, PLL> ClockManager<SOURCE, PLL> {
+    /// Configure PLLSAI1 and start it, or restart it with a new configuration.
+    ///
+    /// While the main PLL or PLLSAI2 runs, PLLSAI1 shares their source and PLLM
+    /// and `config` must use the same PLLM, else this fails with `PLLInputInUse`.
+    /// Otherwise it runs from the system clock oscillator.
+    pub fn enable_pllsai1(self, config: PLLSAIConfig) -> Result<Self, Error> {
+        self.enable_pllsai(PllSai::Sai1, config)
+    }
+
+    /// Configure PLLSAI2 and start it, like `enable_pllsai1`. PLLSAI2 has no
+    /// PLLQ, so a `config` with one fails with `InvalidPLLOutput`.
+    pub fn enable_pllsai2(self, config: PLLSAIConfig) -> Result<Self, Error> {
+        if config.pllq.is_some() {
+            return Err(Error::InvalidPLLOutput);
+        }
+        self.enable_pllsai(PllSai::Sai2, config)
+    }
+
+    /// Stop PLLSAI1. The PLL input is turned off too, unless something else
+    /// runs from it.
+    pub fn disable_pllsai1(self) -> Result<Self, Error> {
+        self.disable_pllsai(PllSai::Sai1)
+    }
+
+    /// Stop PLLSAI2. The PLL input is turned off too, unless something else
+    /// runs from it.
+    pub fn disable_pllsai2(self) -> Result<Self, Error> {
+        self.disable_pllsai(PllSai::Sai2)
+    }
+
+    fn enable_pllsai(mut self, pll: PllSai, config: PLLSAIConfig) -> Result<Self, Error> {
+        let rcc = access::rcc();
+        let cr = rcc.cr().read();
+        let others_on = cr.pllon().bit()
+            || (pll == PllSai::Sai1 && cr.pllsai2on().bit())
+            || (pll == PllSai::Sai2 && cr.pllsai1on().bit());
+
+        // The input is fixed while another PLL runs from it
+        let oscillator = if others_on {
+            if rcc.pllcfgr().read().pllm().bits() + 1 != config.pllm {
+                return Err(Error::PLLInputInUse);
+            }
+            consumers::pll_input()
+        } else {
+            SOURCE::OSCILLATOR
+        };
+        let (pllsrc, input) = match oscillator {
+            Some(Oscillator::Msi) => (SourceMSI::PLLSRC, self.msi_range.freq()),
+            Some(Oscillator::Hsi16) => (SourceHSI16::PLLSRC, HSI16_FREQ),
+            Some(Oscillator::Hse) => (SourceHSE::PLLSRC, self.hse.ok_or(Error::OscillatorNotReady)?),
+            _ => return Err(Error::OscillatorNotReady),
+        };
+        config.validate(input)?;
+        raise_range(
+            config.vco(input) <= RANGE2_VCO_MAX
+                && config.outputs(input).into_iter().flatten().all(|freq| freq <= RANGE2_CLOCK_MAX),
+        )?;
+
+        // PLLSAIxCFGR can only be written while the PLL is off
+        let old_input = consumers::pll_input();
+        self.stop_pllsai(pll)?;
+        if !others_on {
+            rcc.pllcfgr().modify(|_,w| unsafe { w.pllsrc().bits(pllsrc).pllm().bits(config.pllm - 1) });
+        }
+
+        match pll {
+            PllSai::Sai1 => rcc.pllsai1cfgr().write(|w| unsafe {
+                w.pllsai1n().bits(config.plln);
+                if let Some(pllp) = config.pllp {
+                    w.pllsai1pen().set_bit();
+                    w.pllsai1pdiv().bits(pllp);
+                }
+                if let Some(pllq) = config.pllq {
+                    w.pllsai1qen().set_bit();
+                    w.pllsai1q().bits(pllq as u8);
+                }
+                if let Some(pllr) = config.pllr {
+                    w.pllsai1ren().set_bit();
+                    w.pllsai1r().bits(pllr as u8);
+                }
+                w
+            }),
+            PllSai::Sai2 => rcc.pllsai2cfgr().write(|w| unsafe {
+                w.pllsai2n().bits(config.plln);
+                if let Some(pllp) = config.pllp {
+                    w.pllsai2pen().set_bit();
+                    w.pllsai2pdiv().bits(pllp);
+                }
+                if let Some(pllr) = config.pllr {
+                    w.pllsai2ren().set_bit();
+                    w.pllsai2r().bits(pllr as u8);
+                }
+                w
+            }),
+        };
+
+        let ready = || match pll {
+            PllSai::Sai1 => rcc.cr().read().pllsai1rdy().bit_is_set(),
+            PllSai::Sai2 => rcc.cr().read().pllsai2rdy().bit_is_set(),
+        };
+        rcc.cr().modify(|_,w| match pll {
+            PllSai::Sai1 => w.pllsai1on().set_bit(),
+            PllSai::Sai2 => w.pllsai2on().set_bit(),
+        });
+        let result = wait_for(ready, Error::OscillatorNotReady);
+        if result.is_err() {
+            rcc.cr().modify(|_,w| match pll {
+                PllSai::Sai1 => w.pllsai1on().clear_bit(),
+                PllSai::Sai2 => w.pllsai2on().clear_bit(),
+            });
+        }
+
+        // A restart may have moved the PLL input off another oscillator
+        self.release(old_input);
+        result?;
+
+        let enabled = Some(PLLSAIEnabled { config, input });
+        match pll {
+            PllSai::Sai1 => self.pllsai1 = enabled,
+            PllSai::Sai2 => self.pllsai2 = enabled,
+        }
+        Ok(self)
+    }
+
+    fn disable_pllsai(mut self, pll: PllSai) -> Result<Self, Error> {
+        self.stop_pllsai(pll)?;
+        self.release(consumers::pll_input());
+        Ok(self)
+    }
+
+    // Turn off a PLLSAI and wait for it to stop
+    fn stop_pllsai(&mut self, pll: PllSai) -> Result<(), Error> {
+        let rcc = access::rcc();
+        match pll {
+            PllSai::Sai1 => {
+                rcc.cr().modify(|_,w| w.pllsai1on().clear_bit());
+                self.pllsai1 = None;
+            }
+            PllSai::Sai2 => {
+                rcc.cr().modify(|_,w| w.pllsai2on().clear_bit());
+                self.pllsai2 = None;
+            }
+        }
+        let stopped = || match pll {
+            PllSai::Sai1 => rcc.cr().read().pllsai1rdy().bit_is_clear(),
+            PllSai::Sai2 => rcc.cr().read().pllsai2rdy().bit_is_clear(),
+        };
+        wait_for(stopped, Error::OscillatorNotReady)
+    }
+}
+
+// Poll `ready` until it returns true, or fail with `error` after `TIMEOUT` polls
+fn wait_for<F>(ready: F, error: Error) -> Result<(), Error>
+where
+    F: FnMut() -> bool,
+{
+    wait_polls(TIMEOUT, ready, error)
+}
+
+fn wait_polls<F>(polls: u32, mut ready: F, error: Error) -> Result<(), Error>
+where
+    F: FnMut() -> bool,
+{
+    for _ in 0..polls {
+        if ready() {
+            return Ok(());
+        }
+    }
+    Err(error)
+}
+
+// Turn off an oscillator
+fn stop(oscillator: Oscillator) {
+    let rcc = access::rcc();
+    match oscillator {
+        Oscillator::Msi => {
+            rcc.cr().modify(|_,w| w.msion().clear_bit());
+        }
+        Oscillator::Hsi16 => {
+            rcc.cr().modify(|_,w| w.hsion().clear_bit());
+        }
+        Oscillator::Hsi48 => {
+            rcc.crrcr().modify(|_,w| w.hsi48on().clear_bit());
+        }
+        Oscillator::Hse => {
+            rcc.cr().modify(|_,w| w.hseon().clear_bit());
+        }
+        Oscillator::Lse => {
+            backup_domain_access();
+            rcc.bdcr().modify(|_,w| w.lseon().clear_bit());
+        }
+        Oscillator::Lsi => {
+            rcc.csr().modify(|_,w| w.lsion().clear_bit());
+        }
+    }
+}
+
+// Enable the PWR clock if not already
+fn enable_pwr() {
+    if !pac::PWR::is_enabled() {
+        pac::PWR::enable();
+    }
+}
+
+// Allow writes to the backup domain, where the LSE and RTC live
+fn backup_domain_access() {
+    enable_pwr();
+    let pwr = access::pwr();
+    pwr.cr1().modify(|_,w| w.dbp().set_bit());
+}
+
+// Current voltage scaling range. Enables the PWR clock to read it.
+fn current_range() -> VoltageRange {
+    enable_pwr();
+
+    let pwr = access::pwr();
+    if pwr.cr1().read().vos() == VoltageRange::VRange2 as u8 {
+        VoltageRange::VRange2
+    } else {
+        VoltageRange::VRange1
+    }
+}
+
+/// Flash wait states needed at an HCLK of `hclk` Hz in a voltage range. Every
+/// clock transition sets the latency from this.
+pub fn flash_latency(hclk: u32, range: VoltageRange) -> FlashLatency {
+    // Maximum HCLK for 0 - 4 wait states
+    let limits: &[u32] = match range {
+        VoltageRange::VRange2 => &[6_000_000, 12_000_000, 18_000_000, 26_000_000],
+        _ => &[16_000_000, 32_000_000, 48_000_000, 64_000_000],
+    };
+    match limits.iter().position(|limit| hclk <= *limit) {
+        Some(0) => FlashLatency::Latency0,
+        Some(1) => FlashLatency::Latency1,
+        Some(2) => FlashLatency::Latency2,
+        Some(3) => FlashLatency::Latency3,
+        _ => FlashLatency::Latency4,
+    }
+}
+
+// Set the wait states, leaving the prefetch and cache bits alone
+fn set_flash_latency(latency: FlashLatency) {
+    let flash = access::flash();
+    flash.acr().modify(|_,w| unsafe { w.latency().bits(latency as u8) });
+}
+
+// Maximum HCLK in a voltage range
+fn max_hclk(range: VoltageRange) -> u32 {
+    match range {
+        VoltageRange::VRange2 => RANGE2_CLOCK_MAX,
+        _ => 80_000_000,
+    }
+}
+
+// Set `VOS` and wait for the regulator to settle
+fn set_vos(range: VoltageRange) -> Result<(), Error> {
+    let pwr = access::pwr();
+    pwr.cr1().modify(|_,w| unsafe { w.vos().bits(range as u8) });
+    wait_for(|| pwr.sr2().read().vosf().bit_is_clear(), Error::RegulatorNotReady)
+}
+
+// Go up to Range 1 before a clock change, unless the new clocks `fit_range2`.
+// The voltage is raised before the wait states and the clock change, so
+// the core is never overclocked.
+fn raise_range(fit_range2: bool) -> Result<(), Error> {
+    if !fit_range2 && current_range() == VoltageRange::VRange2 {
+        set_vos(VoltageRange::VRange1)?;
+    }
+    Ok(())
+}
+
+// Change HCLK from `from` to `to` Hz with `change`, after checking `to` against
+// the voltage range. The flash wait states are raised before a speed-up and
+// lowered after a slow-down. If `change` fails the wait states are put back.
+fn change_hclk<F>(from: u32, to: u32, change: F) -> Result<(), Error>
+where
+    F: FnOnce() -> Result<(), Error>,
+{
+    raise_range(to <= RANGE2_CLOCK_MAX)?;
+    let range = current_range();
+    if to > max_hclk(range) {
+        return Err(Error::ClockOutOfRange(to));
+    }
+
+    let flash = access::flash();
+    let old_acr = flash.acr().read().bits();
+    let new_latency = flash_latency(to, range);
+    if to > from {
+        set_flash_latency(new_latency);
+    }
+
+    if let Err(error) = change() {
+        flash.acr().write(|w| unsafe { w.bits(old_acr) });
+        return Err(error);
+    }
+
+    if to <= from {
+        set_flash_latency(new_latency);
+    }
+    Ok(())
+}
+
+// Select the system clock with `CFGR.SW`, taking HCLK from `from` to `to` Hz. If
+// the switch times out the previous clock is kept.
+fn switch_sysclk(sw: u8, from: u32, to: u32) -> Result<(), Error> {
+    let rcc = access::rcc();
+    let old_sw = rcc.cfgr().read().sw().bits();
+
+    change_hclk(from, to, || {
+        rcc.cfgr().modify(|_,w| unsafe { w.sw().bits(sw) });
+        let result = wait_for(|| rcc.cfgr().read().sws().bits() == sw, Error::SwitchTimeout);
+        if result.is_err() {
+            rcc.cfgr().modify(|_,w| unsafe { w.sw().bits(old_sw) });
+        }
+        result
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::sim::{self, *};
+    use crate::access::Periph;
+
+    #[test]
+    fn msi_range_sets_latency_first() {
+        sim::reset();
+        let mut clocks = ClockManager::new();
+        clocks.update_msi_range(MSIRange::Range11).unwrap();
+
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 2);
+        assert_eq!((sim::read(Periph::Rcc, RCC_CR) >> 4) & 0xF, 0b1011);
+
+        let writes = sim::writes();
+        let latency = writes.iter().position(|w| w.periph == Periph::Flash && w.offset == FLASH_ACR);
+        let range = writes
+            .iter()
+            .position(|w| w.periph == Periph::Rcc && w.offset == RCC_CR && (w.old ^ w.new) & 0xF0 != 0);
+        assert!(latency.unwrap() < range.unwrap());
+    }
+
+    #[test]
+    fn flash_latency_table() {
+        use FlashLatency::*;
+        use VoltageRange::*;
+
+        let range1 = [
+            (100_000, Latency0), (16_000_000, Latency0), (16_000_001, Latency1),
+            (24_000_000, Latency1), (32_000_000, Latency1), (40_000_000, Latency2),
+            (48_000_000, Latency2), (64_000_000, Latency3), (72_000_000, Latency4),
+            (80_000_000, Latency4),
+        ];
+        let range2 = [
+            (100_000, Latency0), (6_000_000, Latency0), (8_000_000, Latency1),
+            (12_000_000, Latency1), (16_000_000, Latency2), (18_000_000, Latency2),
+            (24_000_000, Latency3), (26_000_000, Latency3),
+        ];
+        for (hclk, latency) in range1 {
+            assert_eq!(flash_latency(hclk, VRange1), latency, "{hclk} Hz in Range 1");
+        }
+        for (hclk, latency) in range2 {
+            assert_eq!(flash_latency(hclk, VRange2), latency, "{hclk} Hz in Range 2");
+        }
+    }
+
+    #[test]
+    fn flash_prefetch_and_caches() {
+        sim::reset();
+        let mut clocks = ClockManager::new();
+        clocks.update_msi_range(MSIRange::Range11).unwrap();
+        // LATENCY 2, the caches keep their reset state
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR), 0x602);
+
+        let mut clocks = clocks.flash_acceleration(true, false);
+        // PRFTEN set, ICEN and DCEN cleared, LATENCY kept
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR), 0x102);
+
+        // Clock changes leave the setting alone
+        clocks.update_msi_range(MSIRange::Range8).unwrap();
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR), 0x100);
+
+        sim::clear_writes();
+        let clocks = clocks.flash_acceleration(false, true);
+        // The caches are reset while still off, then turned on
+        let writes: Vec<u32> = sim::writes_to(Periph::Flash, FLASH_ACR).iter().map(|w| w.new).collect();
+        assert_eq!(writes, [0x1900, 0x100, 0x600]);
+
+        // Caches that are already on aren't reset again
+        sim::clear_writes();
+        clocks.flash_acceleration(true, true);
+        let writes: Vec<u32> = sim::writes_to(Periph::Flash, FLASH_ACR).iter().map(|w| w.new).collect();
+        assert_eq!(writes, [0x700]);
+    }
+
+    #[test]
+    fn msi_range_in_range2() {
+        sim::reset();
+        let mut clocks = ClockManager::new().voltage_range(VoltageRange::VRange2).unwrap();
+        clocks.update_msi_range(MSIRange::Range9).unwrap();
+
+        // 24 MHz fits Range 2 with 3 wait states
+        assert_eq!((sim::read(Periph::Pwr, PWR_CR1) >> 9) & 0b11, 0b10);
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 3);
+
+        // Lowering the range lowers the wait states after the change
+        sim::clear_writes();
+        clocks.update_msi_range(MSIRange::Range5).unwrap();
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 0);
+        let writes = sim::writes();
+        assert_eq!(writes.last().map(|w| w.periph), Some(Periph::Flash));
+    }
+
+    #[test]
+    fn switch_to_hsi() {
+        sim::reset();
+        let clocks = ClockManager::new().switch_to_hsi().unwrap();
+
+        assert_eq!(clocks.sys_clock, 16_000_000);
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & 0b1111, 0b0101);
+        let cr = sim::read(Periph::Rcc, RCC_CR);
+        assert_ne!(cr & (1 << 8), 0);
+        assert_eq!(cr & 1, 0);
+
+        // Down from 48 MHz MSI, the wait states drop after the switch
+        sim::reset();
+        let mut clocks = ClockManager::new();
+        clocks.update_msi_range(MSIRange::Range11).unwrap();
+        sim::clear_writes();
+        clocks.switch_to_hsi().unwrap();
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 0);
+        let writes = sim::writes();
+        let switch = writes.iter().position(|w| w.periph == Periph::Rcc && w.offset == RCC_CFGR);
+        let latency = writes.iter().position(|w| w.periph == Periph::Flash);
+        assert!(switch.unwrap() < latency.unwrap());
+    }
+
+    #[test]
+    fn switch_to_hsi_failures() {
+        // HSI16 never ready: it is turned back off and MSI keeps running
+        sim::reset();
+        sim::clear_scripts();
+        let result = ClockManager::new().switch_to_hsi();
+        assert_eq!(result.err(), Some(Error::OscillatorNotReady));
+        let cr = sim::read(Periph::Rcc, RCC_CR);
+        assert_eq!(cr & (1 << 8), 0);
+        assert_ne!(cr & 1, 0);
+
+        // The switch never happens: SW goes back to the MSI
+        sim::reset();
+        let mut clocks = ClockManager::new();
+        clocks.update_msi_range(MSIRange::Range11).unwrap();
+        sim::clear_scripts();
+        sim::write(Periph::Rcc, RCC_CR, sim::read(Periph::Rcc, RCC_CR) | (1 << 10));
+        let result = clocks.switch_to_hsi();
+        assert_eq!(result.err(), Some(Error::SwitchTimeout));
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & 0b11, 0b00);
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 2);
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & 1, 0);
+    }
+
+    #[test]
+    fn msi_range_timeout() {
+        sim::reset();
+        sim::clear_scripts();
+        sim::script(|regs| {
+            // MSIRDY drops as soon as the range changes and never comes back
+            if regs.read(Periph::Rcc, RCC_CR) & 0xF0 != 0x60 {
+                regs.clear_bits(Periph::Rcc, RCC_CR, 1 << 1);
+            }
+        });
+
+        let mut clocks = ClockManager::new();
+        let result = clocks.update_msi_range(MSIRange::Range11);
+        assert_eq!(result, Err(Error::OscillatorNotReady));
+        assert_eq!((sim::read(Periph::Rcc, RCC_CR) >> 4) & 0xF, 0b0110);
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 0);
+        assert_eq!(clocks.sys_clock, 4_000_000);
+
+        // Still not ready after the rollback, so no range change is tried
+        sim::clear_writes();
+        let result = clocks.update_msi_range(MSIRange::Range8);
+        assert_eq!(result, Err(Error::OscillatorNotReady));
+        assert!(sim::writes_to(Periph::Rcc, RCC_CR).is_empty());
+    }
+
+    #[test]
+    fn msi_not_ready_keeps_range2() {
+        sim::reset();
+        let mut clocks = ClockManager::new().voltage_range(VoltageRange::VRange2).unwrap();
+        sim::clear_scripts();
+        sim::write(Periph::Rcc, RCC_CR, sim::read(Periph::Rcc, RCC_CR) & !(1 << 1));
+        sim::clear_writes();
+
+        // 48 MHz would need Range 1, but the MSI never settles
+        let result = clocks.update_msi_range(MSIRange::Range11);
+        assert_eq!(result, Err(Error::OscillatorNotReady));
+        assert_eq!((sim::read(Periph::Pwr, PWR_CR1) >> 9) & 0b11, 0b10);
+        assert!(sim::writes_to(Periph::Pwr, PWR_CR1).is_empty());
+    }
+
+    #[test]
+    fn pll_config_limits() {
+        assert_eq!(PLLConfig::new(0, 40, PLLDiv::Div2), Err(Error::InvalidPLLM(0)));
+        assert_eq!(PLLConfig::new(9, 40, PLLDiv::Div2), Err(Error::InvalidPLLM(9)));
+        assert_eq!(PLLConfig::new(1, 87, PLLDiv::Div2), Err(Error::InvalidPLLN(87)));
+        assert_eq!(PLLConfig::new(1, 40, PLLDiv::Div2).unwrap().pllp(1), Err(Error::InvalidPLLP(1)));
+
+        // 4 MHz * 40 / 2 = 80 MHz
+        let config = PLLConfig::new(1, 40, PLLDiv::Div2).unwrap();
+        assert_eq!(config.validate(4_000_000), Ok(()));
+        assert_eq!(config.r_clock(4_000_000), 80_000_000);
+        // 2 MHz VCO input
+        assert_eq!(config.validate(2_000_000), Err(Error::VcoInputOutOfRange(2_000_000)));
+        // 16 MHz * 40 = 640 MHz VCO
+        assert_eq!(config.validate(16_000_000), Err(Error::VcoOutputOutOfRange(640_000_000)));
+        // 16 MHz * 20 / 2 = 160 MHz
+        let config = PLLConfig::new(1, 20, PLLDiv::Div2).unwrap();
+        assert_eq!(config.validate(16_000_000), Err(Error::PLLOutputTooHigh(160_000_000)));
+    }
+
+    #[test]
+    fn enable_pll() {
+        sim::reset();
+        let config = PLLConfig::new(1, 40, PLLDiv::Div2).unwrap().pllq(PLLDiv::Div4);
+        let clocks = ClockManager::new().enable_pll(config).unwrap();
+
+        assert_eq!(clocks.pll_clock(), 80_000_000);
+        assert_eq!(clocks.pll_q_clock(), Some(40_000_000));
+        // MSI source, PLLN 40, PLLREN, PLLQEN, PLLQ /4
+        let pllcfgr = sim::read(Periph::Rcc, RCC_PLLCFGR);
+        assert_eq!(pllcfgr, 0b01 | (40 << 8) | (1 << 24) | (1 << 20) | (0b01 << 21));
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & (1 << 25), 0);
+        // Still running from the MSI
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & 0b11, 0b00);
+
+        let clocks = clocks.switch_to_pll().unwrap();
+        assert_eq!(clocks.sys_clock, 80_000_000);
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & 0b1111, 0b1111);
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 4);
+    }
+
+    #[test]
+    fn enable_pll_timeout() {
+        sim::reset();
+        sim::clear_scripts();
+        let config = PLLConfig::new(1, 40, PLLDiv::Div2).unwrap();
+
+        let result = ClockManager::new().enable_pll(config);
+        assert_eq!(result.err(), Some(Error::OscillatorNotReady));
+        assert_eq!(sim::read(Periph::Rcc, RCC_CR) & (1 << 24), 0);
+    }
+
+    #[test]
+    fn pll_input_keeps_msi_on() {
+        sim::reset();
+        let config = PLLConfig::new(1, 40, PLLDiv::Div2).unwrap();
+        let clocks = ClockManager::new().enable_pll(config).unwrap();
+        clocks.switch_to_hsi().unwrap();
+
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & 1, 0);
+    }
+
+    #[test]
+    fn solve_exact() {
+        // MSI 4 MHz and HSI16 to 80 MHz
+        for input in [4_000_000, 16_000_000] {
+            let solution = solve_pll(input, 80_000_000, false).unwrap();
+            assert_eq!(solution.sys_clock, 80_000_000);
+            assert_eq!(solution.error, 0);
+            assert_eq!(solution.config.pllq, None);
+            assert_eq!(solution.config.validate(input), Ok(()));
+        }
+
+        let solution = solve_pll(16_000_000, 80_000_000, false).unwrap();
+        assert_eq!((solution.config.pllm, solution.config.plln), (1, 10));
+        assert_eq!(solution.config.pllr, PLLDiv::Div2);
+    }
+
+    #[test]
+    fn solve_with_usb() {
+        let solution = solve_pll(16_000_000, 48_000_000, true).unwrap();
+        assert_eq!(solution.error, 0);
+        assert_eq!(solution.config.q_clock(16_000_000), Some(48_000_000));
+
+        // No VCO gives both 80 MHz and 48 MHz
+        for input in [8_000_000, 16_000_000] {
+            let solution = solve_pll(input, 80_000_000, true).unwrap();
+            assert_eq!(solution.sys_clock, 72_000_000);
+            assert_eq!(solution.error, 8_000_000);
+            assert_eq!(solution.config.q_clock(input), Some(48_000_000));
+        }
+    }
+
+    #[test]
+    fn solve_inexact() {
+        // 25 MHz HSE to 72 MHz
+        let solution = solve_pll(25_000_000, 72_000_000, false).unwrap();
+        assert_eq!(solution.config.r_clock(25_000_000), solution.sys_clock);
+        assert!(solution.error <= 1_000_000);
+        assert_eq!(solution.config.validate(25_000_000), Ok(()));
+
+        // Above the PLL limit the closest is 80 MHz
+        let solution = solve_pll(16_000_000, 120_000_000, false).unwrap();
+        assert_eq!(solution.sys_clock, 80_000_000);
+    }
+
+    #[test]
+    fn solve_impossible() {
+        // VCO input can't reach 4 MHz
+        assert_eq!(solve_pll(1_000_000, 80_000_000, false), None);
+    }
+
+    #[test]
+    fn switch_to_hse_crystal() {
+        sim::reset();
+        let clocks = ClockManager::new().switch_to_hse(8_000_000, HseMode::Crystal).unwrap();
+
+        assert_eq!(clocks.sys_clock, 8_000_000);
+        let cr = sim::read(Periph::Rcc, RCC_CR);
+        assert_ne!(cr & (1 << 16), 0);
+        assert_eq!(cr & (1 << 18), 0);
+        // MSI is off
+        assert_eq!(cr & 1, 0);
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & 0b1111, 0b1010);
+    }
+
+    #[test]
+    fn switch_to_hse_bypass() {
+        sim::reset();
+        let clocks = ClockManager::new()
+            .switch_to_hsi()
+            .unwrap()
+            .switch_to_hse(48_000_000, HseMode::Bypass)
+            .unwrap();
+
+        assert_eq!(clocks.sys_clock, 48_000_000);
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 2);
+        // HSEBYP is set before HSEON
+        let cr: Vec<u32> = sim::writes_to(Periph::Rcc, RCC_CR).iter().map(|w| w.new).collect();
+        let bypass = cr.iter().position(|cr| cr & (1 << 18) != 0).unwrap();
+        let on = cr.iter().position(|cr| cr & (1 << 16) != 0).unwrap();
+        assert!(bypass < on);
+        // HSI16 is off
+        assert_eq!(sim::read(Periph::Rcc, RCC_CR) & (1 << 8), 0);
+    }
+
+    #[test]
+    fn switch_to_hse_errors() {
+        sim::reset();
+        let result = ClockManager::new().switch_to_hse(2_000_000, HseMode::Crystal);
+        assert_eq!(result.err(), Some(Error::HseOutOfRange(2_000_000)));
+        let result = ClockManager::new().switch_to_hse(50_000_000, HseMode::Bypass);
+        assert_eq!(result.err(), Some(Error::HseOutOfRange(50_000_000)));
+
+        // No crystal fitted
+        sim::clear_scripts();
+        let result = ClockManager::new().switch_to_hse(8_000_000, HseMode::Crystal);
+        assert_eq!(result.err(), Some(Error::OscillatorNotReady));
+        let cr = sim::read(Periph::Rcc, RCC_CR);
+        assert_eq!(cr & (1 << 16), 0);
+        assert_ne!(cr & 1, 0);
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & 0b11, 0b00);
+    }
+
+    #[test]
+    fn hse_pll() {
+        sim::reset();
+        let solution = solve_pll(8_000_000, 80_000_000, false).unwrap();
+        let clocks = ClockManager::new()
+            .switch_to_hse(8_000_000, HseMode::Crystal)
+            .unwrap()
+            .enable_pll(solution.config)
+            .unwrap()
+            .switch_to_pll()
+            .unwrap();
+
+        assert_eq!(clocks.sys_clock, 80_000_000);
+        assert_eq!(sim::read(Periph::Rcc, RCC_PLLCFGR) & 0b11, 0b11);
+        // Back to the HSE, which is still running the PLL
+        let clocks = clocks.switch_to_hse(8_000_000, HseMode::Crystal).unwrap();
+        assert_eq!(clocks.sys_clock, 8_000_000);
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & (1 << 24), 0);
+    }
+
+    #[test]
+    fn hse_frequency_mismatch() {
+        sim::reset();
+        let solution = solve_pll(8_000_000, 80_000_000, false).unwrap();
+        let clocks = ClockManager::new()
+            .switch_to_hse(8_000_000, HseMode::Crystal)
+            .unwrap()
+            .enable_pll(solution.config)
+            .unwrap()
+            .switch_to_pll()
+            .unwrap();
+        sim::clear_writes();
+
+        // The running HSE keeps its frequency
+        let result = clocks.switch_to_hse(16_000_000, HseMode::Crystal);
+        assert_eq!(result.err(), Some(Error::HseFrequencyMismatch(8_000_000)));
+        assert!(sim::writes().is_empty());
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & 0b11, 0b11);
+    }
+
+    #[test]
+    fn freeze() {
+        sim::reset();
+        let config = PLLConfig::new(1, 20, PLLDiv::Div2).unwrap().pllq(PLLDiv::Div4);
+        let clocks = ClockManager::new()
+            .switch_to_hse(8_000_000, HseMode::Crystal)
+            .unwrap()
+            .enable_pll(config)
+            .unwrap()
+            .switch_to_pll()
+            .unwrap()
+            .freeze();
+
+        assert_eq!(clocks.sysclk(), 80_000_000);
+        assert_eq!(clocks.hclk(), 80_000_000);
+        assert_eq!(clocks.pclk1(), 80_000_000);
+        assert_eq!(clocks.timclk2(), 80_000_000);
+        assert_eq!(clocks.hse(), Some(8_000_000));
+        assert_eq!(clocks.msi(), None);
+        assert_eq!(clocks.pllclk(), Some(80_000_000));
+        // 8 MHz * 20 / 4
+        assert_eq!(clocks.pllq(), Some(40_000_000));
+        assert_eq!(clocks.pllp(), None);
+        // Reset kernel clock selections
+        assert_eq!(clocks.kernel(Kernel::Usart1), Some(80_000_000));
+        assert_eq!(clocks.kernel(Kernel::I2c1), Some(80_000_000));
+        assert_eq!(clocks.kernel(Kernel::Adc), None);
+        assert_eq!(clocks.kernel(Kernel::Clk48), None);
+    }
+
+    #[test]
+    fn freeze_kernel_selection() {
+        sim::reset();
+        // USART2 from HSI16, I2C1 from SYSCLK, LPTIM1 from LSE, CLK48 from MSI
+        sim::write(Periph::Rcc, RCC_CCIPR, (0b10 << 2) | (0b01 << 12) | (0b11 << 18) | (0b11 << 26));
+        let clocks = ClockManager::new().freeze();
+
+        assert_eq!(clocks.sysclk(), 4_000_000);
+        assert_eq!(clocks.msi(), Some(4_000_000));
+        // HSI16 is off
+        assert_eq!(clocks.kernel(Kernel::Usart2), None);
+        assert_eq!(clocks.kernel(Kernel::I2c1), Some(4_000_000));
+        assert_eq!(clocks.kernel(Kernel::Lptim1), None);
+        assert_eq!(clocks.kernel(Kernel::Clk48), Some(4_000_000));
+    }
+
+    #[test]
+    fn bus_prescalers() {
+        sim::reset();
+        let solution = solve_pll(4_000_000, 80_000_000, false).unwrap();
+        let clocks = ClockManager::new()
+            .apb1_prescaler(APBPrescaler::Div4)
+            .apb2_prescaler(APBPrescaler::Div2)
+            .enable_pll(solution.config)
+            .unwrap()
+            .switch_to_pll()
+            .unwrap()
+            .freeze();
+
+        assert_eq!(clocks.hclk(), 80_000_000);
+        assert_eq!(clocks.pclk1(), 20_000_000);
+        assert_eq!(clocks.timclk1(), 40_000_000);
+        assert_eq!(clocks.pclk2(), 40_000_000);
+        assert_eq!(clocks.timclk2(), 80_000_000);
+        assert_eq!(clocks.kernel(Kernel::Usart2), Some(20_000_000));
+        let cfgr = sim::read(Periph::Rcc, RCC_CFGR);
+        assert_eq!((cfgr >> 8) & 0b111, 0b101);
+        assert_eq!((cfgr >> 11) & 0b111, 0b100);
+    }
+
+    #[test]
+    fn ahb_prescaler_sets_latency() {
+        sim::reset();
+        let solution = solve_pll(4_000_000, 80_000_000, false).unwrap();
+        let clocks = ClockManager::new()
+            .ahb_prescaler(AHBPrescaler::Div2)
+            .unwrap()
+            .enable_pll(solution.config)
+            .unwrap()
+            .switch_to_pll()
+            .unwrap();
+
+        // 40 MHz HCLK
+        assert_eq!((sim::read(Periph::Rcc, RCC_CFGR) >> 4) & 0xF, 0b1000);
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 2);
+
+        // Wait states go up before HPRE does
+        sim::clear_writes();
+        let clocks = clocks.ahb_prescaler(AHBPrescaler::Div1).unwrap();
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 4);
+        let writes = sim::writes();
+        assert_eq!((writes[0].periph, writes[0].offset), (Periph::Flash, FLASH_ACR));
+        assert_eq!((writes[1].periph, writes[1].offset), (Periph::Rcc, RCC_CFGR));
+
+        // And come down after it
+        sim::clear_writes();
+        let clocks = clocks.ahb_prescaler(AHBPrescaler::Div16).unwrap();
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 0);
+        let writes = sim::writes();
+        assert_eq!((writes[0].periph, writes[0].offset), (Periph::Rcc, RCC_CFGR));
+        assert_eq!((writes[1].periph, writes[1].offset), (Periph::Flash, FLASH_ACR));
+        assert_eq!(clocks.freeze().hclk(), 5_000_000);
+    }
+
+    #[test]
+    fn range2() {
+        sim::reset();
+        let clocks = ClockManager::new().switch_to_hsi().unwrap().voltage_range(VoltageRange::VRange2).unwrap();
+
+        assert_eq!((sim::read(Periph::Pwr, PWR_CR1) >> 9) & 0b11, 0b10);
+        // 16 MHz needs 2 wait states in Range 2, set before the voltage drops
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 2);
+        let writes = sim::writes();
+        let latency = writes.iter().rposition(|w| w.periph == Periph::Flash).unwrap();
+        let vos = writes.iter().rposition(|w| w.periph == Periph::Pwr && w.offset == PWR_CR1).unwrap();
+        assert!(latency < vos);
+
+        // Back up, then the wait states come down
+        sim::clear_writes();
+        clocks.voltage_range(VoltageRange::VRange1).unwrap();
+        assert_eq!((sim::read(Periph::Pwr, PWR_CR1) >> 9) & 0b11, 0b01);
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 0);
+        let writes = sim::writes();
+        assert_eq!((writes[0].periph, writes[0].offset), (Periph::Pwr, PWR_CR1));
+    }
+
+    #[test]
+    fn range2_refused() {
+        sim::reset();
+        let solution = solve_pll(4_000_000, 80_000_000, false).unwrap();
+        let clocks = ClockManager::new().enable_pll(solution.config).unwrap();
+
+        // The PLL runs at 80 MHz even before SYSCLK moves to it
+        let result = clocks.voltage_range(VoltageRange::VRange2);
+        assert_eq!(result.err(), Some(Error::ClockOutOfRange(160_000_000)));
+        assert_eq!((sim::read(Periph::Pwr, PWR_CR1) >> 9) & 0b11, 0b01);
+
+        sim::reset();
+        let result = ClockManager::new().voltage_range(VoltageRange::VRange1Boost);
+        assert_eq!(result.err(), Some(Error::InvalidRange));
+    }
+
+    #[test]
+    fn range2_raised_for_clock_change() {
+        sim::reset();
+        let clocks = ClockManager::new().voltage_range(VoltageRange::VRange2).unwrap();
+        sim::clear_writes();
+
+        let solution = solve_pll(4_000_000, 80_000_000, false).unwrap();
+        let clocks = clocks.enable_pll(solution.config).unwrap().switch_to_pll().unwrap();
+        assert_eq!(clocks.sys_clock, 80_000_000);
+        assert_eq!((sim::read(Periph::Pwr, PWR_CR1) >> 9) & 0b11, 0b01);
+
+        // Voltage, then PLL, then wait states, then the switch
+        let writes = sim::writes();
+        let position = |periph, offset| writes.iter().position(|w| w.periph == periph && w.offset == offset).unwrap();
+        assert!(position(Periph::Pwr, PWR_CR1) < position(Periph::Rcc, RCC_PLLCFGR));
+        assert!(position(Periph::Rcc, RCC_PLLCFGR) < position(Periph::Flash, FLASH_ACR));
+        assert!(position(Periph::Flash, FLASH_ACR) < position(Periph::Rcc, RCC_CFGR));
+
+        // HSE above 26 MHz needs Range 1 too, even with a slow HCLK
+        sim::reset();
+        let clocks = ClockManager::new()
+            .ahb_prescaler(AHBPrescaler::Div2)
+            .unwrap()
+            .voltage_range(VoltageRange::VRange2)
+            .unwrap()
+            .switch_to_hse(48_000_000, HseMode::Bypass)
+            .unwrap();
+        assert_eq!((sim::read(Periph::Pwr, PWR_CR1) >> 9) & 0b11, 0b01);
+        assert_eq!(clocks.freeze().hclk(), 24_000_000);
+    }
+
+    #[test]
+    fn regulator_timeout() {
+        sim::reset();
+        sim::write(Periph::Pwr, PWR_SR2, 1 << 10);
+
+        let result = ClockManager::new().voltage_range(VoltageRange::VRange2);
+        assert_eq!(result.err(), Some(Error::RegulatorNotReady));
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 0);
+    }
+
+    #[test]
+    fn lse() {
+        sim::reset();
+        let clocks = ClockManager::new().enable_lse(LseMode::Crystal(LseDrive::MediumHigh)).unwrap();
+
+        // DBP
+        assert_ne!(sim::read(Periph::Pwr, PWR_CR1) & (1 << 8), 0);
+        let bdcr = sim::read(Periph::Rcc, RCC_BDCR);
+        assert_eq!(bdcr & 0b11111, 0b10011);
+        assert_eq!(clocks.freeze().lse(), Some(32_768));
+
+        sim::reset();
+        let clocks = ClockManager::new().enable_lse(LseMode::Bypass).unwrap();
+        assert_eq!(sim::read(Periph::Rcc, RCC_BDCR) & 0b111, 0b111);
+        let frozen = clocks.freeze();
+        assert_eq!(frozen.lse(), Some(32_768));
+        assert_eq!(frozen.lsi(), None);
+    }
+
+    #[test]
+    fn lse_css() {
+        sim::reset();
+        let result = ClockManager::new().enable_lse_css();
+        assert_eq!(result.err(), Some(Error::OscillatorNotReady));
+
+        let clocks = ClockManager::new()
+            .enable_lse(LseMode::Crystal(LseDrive::Low))
+            .unwrap()
+            .enable_lse_css()
+            .unwrap();
+        assert_ne!(sim::read(Periph::Rcc, RCC_BDCR) & (1 << 5), 0);
+        assert_eq!(sim::read(Periph::Rcc, RCC_CSR) & 0b11, 0b11);
+        assert_eq!(clocks.freeze().lsi(), Some(32_000));
+
+        // LSE failure detected
+        sim::write(Periph::Rcc, RCC_BDCR, sim::read(Periph::Rcc, RCC_BDCR) | (1 << 6));
+        let clocks = ClockManager::new();
+        assert_eq!(clocks.freeze().lse(), None);
+        let result = ClockManager::new().msi_pll_mode(true);
+        assert_eq!(result.err(), Some(Error::OscillatorNotReady));
+    }
+
+    #[test]
+    fn lsi_timeout() {
+        sim::reset();
+        sim::clear_scripts();
+        let result = ClockManager::new().enable_lsi();
+        assert_eq!(result.err(), Some(Error::OscillatorNotReady));
+        assert_eq!(sim::read(Periph::Rcc, RCC_CSR) & 1, 0);
+    }
+
+    #[test]
+    fn msi_pll_mode() {
+        sim::reset();
+        let result = ClockManager::new().msi_pll_mode(true);
+        assert_eq!(result.err(), Some(Error::OscillatorNotReady));
+        assert_eq!(sim::read(Periph::Rcc, RCC_CR) & (1 << 2), 0);
+
+        let clocks = ClockManager::new()
+            .enable_lse(LseMode::Crystal(LseDrive::Low))
+            .unwrap()
+            .msi_pll_mode(true)
+            .unwrap();
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & (1 << 2), 0);
+        assert!(clocks.freeze().msi_pll_mode());
+
+        let clocks = ClockManager::new().msi_pll_mode(false).unwrap();
+        assert_eq!(sim::read(Periph::Rcc, RCC_CR) & (1 << 2), 0);
+        assert!(!clocks.freeze().msi_pll_mode());
+    }
+
+    #[test]
+    fn back_to_msi() {
+        sim::reset();
+        let clocks = ClockManager::new()
+            .switch_to_hsi()
+            .unwrap()
+            .switch_to_msi(MSIRange::Range8)
+            .unwrap();
+
+        assert_eq!(clocks.sys_clock, 16_000_000);
+        let cr = sim::read(Periph::Rcc, RCC_CR);
+        assert_eq!(cr & 0b1011, 0b1011);
+        assert_eq!((cr >> 4) & 0xF, 0b1000);
+        assert_eq!(cr & (1 << 8), 0);
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & 0b1111, 0);
+
+        // HSE off once nothing runs from it
+        let clocks = clocks.switch_to_hse(8_000_000, HseMode::Crystal).unwrap().switch_to_hsi().unwrap();
+        assert_eq!(sim::read(Periph::Rcc, RCC_CR) & ((1 << 16) | 1), 0);
+        let frozen = clocks.freeze();
+        assert_eq!(frozen.hse(), None);
+        assert_eq!(frozen.msi(), None);
+        assert_eq!(frozen.hsi16(), Some(16_000_000));
+    }
+
+    #[test]
+    fn leave_pll() {
+        sim::reset();
+        let solution = solve_pll(8_000_000, 80_000_000, false).unwrap();
+        let clocks = ClockManager::new()
+            .switch_to_hse(8_000_000, HseMode::Crystal)
+            .unwrap()
+            .enable_pll(solution.config)
+            .unwrap()
+            .switch_to_pll()
+            .unwrap()
+            .switch_to_msi(MSIRange::Range6)
+            .unwrap();
+
+        // The PLL keeps its HSE input
+        let cr = sim::read(Periph::Rcc, RCC_CR);
+        assert_ne!(cr & (1 << 16), 0);
+        assert_ne!(cr & (1 << 24), 0);
+        assert_eq!(sim::read(Periph::Flash, FLASH_ACR) & 0b111, 0);
+
+        let clocks = clocks.disable_pll().unwrap();
+        let cr = sim::read(Periph::Rcc, RCC_CR);
+        assert_eq!(cr & ((1 << 24) | (1 << 16)), 0);
+        assert_ne!(cr & 1, 0);
+        assert_eq!(clocks.freeze().hse(), None);
+
+        // Disabling a PLL fed by the system clock leaves that running
+        sim::reset();
+        let config = PLLConfig::new(1, 10, PLLDiv::Div2).unwrap();
+        ClockManager::new()
+            .switch_to_hsi()
+            .unwrap()
+            .enable_pll(config)
+            .unwrap()
+            .disable_pll()
+            .unwrap();
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & (1 << 8), 0);
+    }
+
+    #[test]
+    fn msi_in_use() {
+        sim::reset();
+        let config = PLLConfig::new(1, 40, PLLDiv::Div2).unwrap();
+        let clocks = ClockManager::new().enable_pll(config).unwrap().switch_to_pll().unwrap();
+
+        let result = clocks.switch_to_msi(MSIRange::Range8);
+        assert_eq!(result.err(), Some(Error::OscillatorInUse));
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & 0b11, 0b11);
+
+        sim::reset();
+        let clocks = ClockManager::new().enable_pll(config).unwrap().switch_to_pll().unwrap();
+        let clocks = clocks.switch_to_msi(MSIRange::Range6).unwrap();
+        assert_eq!(clocks.sys_clock, 4_000_000);
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & 0b11, 0b00);
+    }
+
+    #[test]
+    fn kernel_keeps_oscillator() {
+        sim::reset();
+        let clocks = ClockManager::new();
+        let consumers = clocks.consumers(Oscillator::Msi);
+        assert_eq!(consumers.count(), 1);
+        assert!(consumers.contains(Consumer::SysClk));
+
+        // USB from the MSI
+        sim::write(Periph::Rcc, RCC_CCIPR, 0b11 << 26);
+        let clocks = clocks.switch_to_hsi().unwrap();
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & 1, 0);
+        let consumers = clocks.consumers(Oscillator::Msi);
+        assert_eq!(consumers.iter().collect::<Vec<_>>(), [Consumer::Kernel(Kernel::Clk48)]);
+
+        // USART2 and LPTIM1 from the HSI16 as well as SYSCLK
+        sim::write(Periph::Rcc, RCC_CCIPR, (0b10 << 2) | (0b10 << 18));
+        let consumers = clocks.consumers(Oscillator::Hsi16);
+        assert_eq!(
+            consumers.iter().collect::<Vec<_>>(),
+            [Consumer::Kernel(Kernel::Usart2), Consumer::Kernel(Kernel::Lptim1), Consumer::SysClk]
+        );
+        assert!(clocks.consumers(Oscillator::Msi).is_empty());
+    }
+
+    #[test]
+    fn wakeup_keeps_oscillator() {
+        sim::reset();
+        let clocks = ClockManager::new()
+            .stop_wakeup_clock(WakeupClock::Msi)
+            .unwrap()
+            .switch_to_hsi()
+            .unwrap();
+        assert_eq!(sim::read(Periph::Rcc, RCC_CFGR) & (1 << 15), 0);
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & 1, 0);
+        assert!(clocks.consumers(Oscillator::Msi).contains(Consumer::Wakeup));
+
+        // Moving the wakeup clock to the HSI16 lets the MSI go
+        let clocks = clocks.stop_wakeup_clock(WakeupClock::Hsi16).unwrap();
+        assert_ne!(sim::read(Periph::Rcc, RCC_CFGR) & (1 << 15), 0);
+        assert_eq!(sim::read(Periph::Rcc, RCC_CR) & 1, 0);
+        assert_eq!(clocks.consumers(Oscillator::Hsi16).count(), 2);
+    }
+
+    #[test]
+    fn low_speed_consumers() {
+        sim::reset();
+        let clocks = ClockManager::new()
+            .enable_lse(LseMode::Crystal(LseDrive::Low))
+            .unwrap()
+            .msi_pll_mode(true)
+            .unwrap();
+        // RTC from the LSE
+        sim::write(Periph::Rcc, RCC_BDCR, sim::read(Periph::Rcc, RCC_BDCR) | (1 << 15) | (0b01 << 8));
+
+        let consumers = clocks.consumers(Oscillator::Lse);
+        assert!(consumers.contains(Consumer::Rtc));
+        assert!(consumers.contains(Consumer::MsiPll));
+        let result = clocks.disable_lse();
+        assert_eq!(result.err(), Some(Error::OscillatorInUse));
+        assert_ne!(sim::read(Periph::Rcc, RCC_BDCR) & 1, 0);
+
+        // The LSI goes once the LSE clock security system doesn't need it
+        sim::reset();
+        let clocks = ClockManager::new().enable_lsi().unwrap().disable_lsi().unwrap();
+        assert_eq!(sim::read(Periph::Rcc, RCC_CSR) & 1, 0);
+        let result = clocks
+            .enable_lse(LseMode::Bypass)
+            .unwrap()
+            .enable_lse_css()
+            .unwrap()
+            .disable_lsi();
+        assert_eq!(result.err(), Some(Error::OscillatorInUse));
+    }
+
+    #[test]
+    fn kernel_clock_selection() {
+        sim::reset();
+        let clocks = ClockManager::new()
+            .kernel_clock(KernelClock::Usart2(UsartClock::Hsi16))
+            .unwrap()
+            .kernel_clock(KernelClock::I2c4(I2cClock::SysClk))
+            .unwrap()
+            .kernel_clock(KernelClock::Lptim1(LptimClock::Lsi))
+            .unwrap()
+            .kernel_clock(KernelClock::Dfsdm1(DfsdmClock::SysClk))
+            .unwrap();
+        assert_eq!(sim::read(Periph::Rcc, RCC_CCIPR), (0b10 << 2) | (0b01 << 18) | (1 << 31));
+        assert_eq!(sim::read(Periph::Rcc, RCC_CCIPR2), 0b01);
+        // HSI16 and LSI started
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & (1 << 8), 0);
+        assert_ne!(sim::read(Periph::Rcc, RCC_CSR) & 1, 0);
+
+        let clocks = clocks.freeze();
+        assert_eq!(clocks.kernel(Kernel::Usart2), Some(16_000_000));
+        assert_eq!(clocks.kernel(Kernel::Usart3), Some(4_000_000));
+        assert_eq!(clocks.kernel(Kernel::I2c4), Some(4_000_000));
+        assert_eq!(clocks.kernel(Kernel::Lptim1), Some(32_000));
+        assert_eq!(clocks.kernel(Kernel::Dfsdm1), Some(4_000_000));
+    }
+
+    #[test]
+    fn kernel_clock_release() {
+        sim::reset();
+        let clocks = ClockManager::new()
+            .kernel_clock(KernelClock::Clk48(Clk48Clock::Hsi48))
+            .unwrap()
+            .kernel_clock(KernelClock::I2c4(I2cClock::Hsi16))
+            .unwrap();
+        assert_ne!(sim::read(Periph::Rcc, RCC_CRRCR) & 1, 0);
+        assert_eq!(sim::read(Periph::Rcc, RCC_CCIPR2), 0b10);
+        assert_eq!(
+            clocks.consumers(Oscillator::Hsi16).iter().collect::<Vec<_>>(),
+            [Consumer::Kernel(Kernel::I2c4)]
+        );
+        // Same hardware state, frozen through a second manager
+        let frozen = ClockManager::new().freeze();
+        assert_eq!(frozen.kernel(Kernel::Clk48), Some(48_000_000));
+        assert_eq!(frozen.kernel(Kernel::I2c4), Some(16_000_000));
+
+        // Moving away stops the oscillators nothing else uses
+        let clocks = clocks
+            .kernel_clock(KernelClock::Clk48(Clk48Clock::Msi))
+            .unwrap()
+            .kernel_clock(KernelClock::I2c4(I2cClock::Pclk1))
+            .unwrap();
+        assert_eq!(sim::read(Periph::Rcc, RCC_CRRCR) & 1, 0);
+        assert_eq!(sim::read(Periph::Rcc, RCC_CR) & (1 << 8), 0);
+        assert_eq!(clocks.freeze().kernel(Kernel::Clk48), Some(4_000_000));
+    }
+
+    #[test]
+    fn kernel_clock_needs_lse() {
+        sim::reset();
+        let result = ClockManager::new().kernel_clock(KernelClock::Lpuart1(UsartClock::Lse));
+        assert_eq!(result.err(), Some(Error::OscillatorNotReady));
+        assert_eq!(sim::read(Periph::Rcc, RCC_CCIPR), 0);
+
+        let clocks = ClockManager::new()
+            .enable_lse(LseMode::Bypass)
+            .unwrap()
+            .kernel_clock(KernelClock::Lpuart1(UsartClock::Lse))
+            .unwrap();
+        assert_eq!(sim::read(Periph::Rcc, RCC_CCIPR), 0b11 << 10);
+        assert!(clocks.consumers(Oscillator::Lse).contains(Consumer::Kernel(Kernel::Lpuart1)));
+        assert_eq!(clocks.freeze().kernel(Kernel::Lpuart1), Some(32_768));
+    }
+
+    #[test]
+    fn solve_pllsai_outputs() {
+        // 4 MHz * 32 / 2
+        let solution = solve_pllsai(4_000_000, None, PLLSAIOutput::R, 64_000_000).unwrap();
+        assert_eq!(solution.config, PLLSAIConfig::new(1, 32).unwrap().pllr(PLLDiv::Div2));
+        assert_eq!(solution.error, 0);
+
+        let solution = solve_pllsai(4_000_000, Some(1), PLLSAIOutput::P, 12_000_000).unwrap();
+        assert_eq!(solution.config, PLLSAIConfig::new(1, 18).unwrap().pllp(6).unwrap());
+        assert_eq!(solution.freq, 12_000_000);
+
+        // 48 kHz * 256 for the SAI isn't exact from the HSI16
+        let solution = solve_pllsai(16_000_000, None, PLLSAIOutput::P, 12_288_000).unwrap();
+        assert_eq!(solution.config, PLLSAIConfig::new(3, 53).unwrap().pllp(23).unwrap());
+        assert_eq!(solution.freq, 12_289_855);
+        assert_eq!(solution.error, 1_855);
+
+        assert_eq!(solve_pllsai(2_000_000, Some(1), PLLSAIOutput::Q, 48_000_000), None);
+        assert_eq!(PLLSAIConfig::new(9, 16), Err(Error::InvalidPLLM(9)));
+        assert_eq!(PLLSAIConfig::new(1, 16).unwrap().pllp(1), Err(Error::InvalidPLLP(1)));
+    }
+
+    #[test]
+    fn enable_pllsai() {
+        sim::reset();
+        // 4 MHz MSI * 24: 12 MHz SAI, 48 MHz USB and ADC clocks
+        let config = PLLSAIConfig::new(1, 24).unwrap().pllp(8).unwrap().pllq(PLLDiv::Div2).pllr(PLLDiv::Div2);
+        let clocks = ClockManager::new()
+            .enable_pllsai1(config)
+            .unwrap()
+            .kernel_clock(KernelClock::Clk48(Clk48Clock::PllSai1Q))
+            .unwrap()
+            .kernel_clock(KernelClock::Adc(AdcClock::PllSai1R))
+            .unwrap();
+
+        assert_eq!(sim::read(Periph::Rcc, RCC_PLLCFGR) & 0b111_0011, 0b01);
+        assert_eq!(
+            sim::read(Periph::Rcc, RCC_PLLSAI1CFGR),
+            (24 << 8) | (1 << 16) | (1 << 20) | (1 << 24) | (8 << 27)
+        );
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & (1 << 27), 0);
+        assert!(clocks.consumers(Oscillator::Msi).contains(Consumer::PllInput));
+
+        let clocks = clocks.freeze();
+        assert_eq!(clocks.pllsai1p(), Some(12_000_000));
+        assert_eq!(clocks.pllsai1q(), Some(48_000_000));
+        assert_eq!(clocks.pllsai1r(), Some(48_000_000));
+        assert_eq!(clocks.pllsai2p(), None);
+        assert_eq!(clocks.pllclk(), None);
+        // Both SAIs run from PLLSAI1P out of reset
+        assert_eq!(clocks.kernel(Kernel::Sai1), Some(12_000_000));
+        assert_eq!(clocks.kernel(Kernel::Sai2), Some(12_000_000));
+        assert_eq!(clocks.kernel(Kernel::Clk48), Some(48_000_000));
+        assert_eq!(clocks.kernel(Kernel::Adc), Some(48_000_000));
+    }
+
+    #[test]
+    fn pllsai_shares_input() {
+        sim::reset();
+        let config = PLLSAIConfig::new(1, 32).unwrap().pllp(8).unwrap();
+        let clocks = ClockManager::new().enable_pllsai1(config).unwrap();
+
+        // PLLSAI2 has no PLLQ, and the PLLs share PLLM and the source
+        let result = ClockManager::new().enable_pllsai2(config.pllq(PLLDiv::Div4));
+        assert_eq!(result.err(), Some(Error::InvalidPLLOutput));
+        let result = ClockManager::new().enable_pllsai2(PLLSAIConfig::new(2, 32).unwrap());
+        assert_eq!(result.err(), Some(Error::PLLInputInUse));
+        let pll = PLLConfig::new(1, 10, PLLDiv::Div2).unwrap();
+        let result = ClockManager::new().switch_to_hsi().unwrap().enable_pll(pll);
+        assert_eq!(result.err(), Some(Error::PLLInputInUse));
+
+        // The MSI stays on for PLLSAI1, at the same range
+        let clocks = clocks.switch_to_hsi().unwrap();
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & 1, 0);
+        let result = ClockManager::new().update_msi_range(MSIRange::Range8);
+        assert_eq!(result.err(), Some(Error::OscillatorInUse));
+
+        // PLLSAI2 runs from the MSI too, whatever the system clock
+        let clocks = clocks.enable_pllsai2(config).unwrap();
+        assert_eq!(clocks.freeze().pllsai2p(), Some(16_000_000));
+        let clocks = ClockManager::new()
+            .switch_to_hsi()
+            .unwrap()
+            .disable_pllsai1()
+            .unwrap();
+        assert_ne!(sim::read(Periph::Rcc, RCC_CR) & 1, 0);
+        clocks.disable_pllsai2().unwrap();
+        assert_eq!(sim::read(Periph::Rcc, RCC_CR) & (1 << 26 | 1 << 28 | 1), 0);
+    }
+
+    #[test]
+    fn msi_range_with_pll_enabled() {
+        sim::reset();
+        // The PLL runs from the HSI16, so the MSI range is free to change
+        let config = PLLConfig::new(1, 10, PLLDiv::Div2).unwrap();
+        let mut clocks = ClockManager::new()
+            .switch_to_hsi()
+            .unwrap()
+            .enable_pll(config)
+            .unwrap()
+            .switch_to_msi(MSIRange::Range6)
+            .unwrap();
+        clocks.update_msi_range(MSIRange::Range8).unwrap();
+        assert_eq!(clocks.sys_clock, 16_000_000);
+        assert_eq!((sim::read(Periph::Rcc, RCC_CR) >> 4) & 0xF, 0b1000);
+
+        // Not while it runs from the MSI
+        sim::reset();
+        let config = PLLConfig::new(1, 40, PLLDiv::Div2).unwrap();
+        let mut clocks = ClockManager::new().enable_pll(config).unwrap();
+        sim::clear_writes();
+        let result = clocks.update_msi_range(MSIRange::Range8);
+        assert_eq!(result, Err(Error::OscillatorInUse));
+        assert!(sim::writes_to(Periph::Rcc, RCC_CR).is_empty());
+    }
+
+    #[test]
+    fn msi_range_refused_keeps_range2() {
+        sim::reset();
+        let config = PLLSAIConfig::new(1, 16).unwrap().pllp(4).unwrap();
+        ClockManager::new()
+            .voltage_range(VoltageRange::VRange2)
+            .unwrap()
+            .enable_pllsai1(config)
+            .unwrap();
+        sim::clear_writes();
+
+        // 48 MHz would need Range 1, but PLLSAI1 runs from the MSI
+        let result = ClockManager::new().update_msi_range(MSIRange::Range11);
+        assert_eq!(result.err(), Some(Error::OscillatorInUse));
+        assert_eq!((sim::read(Periph::Pwr, PWR_CR1) >> 9) & 0b11, 0b10);
+        assert!(sim::writes_to(Periph::Pwr, PWR_CR1).is_empty());
+    }
+
+    #[test]
+    fn pllsai_range2() {
+        sim::reset();
+        let config = PLLSAIConfig::new(1, 24).unwrap().pllr(PLLDiv::Div2);
+        let result = ClockManager::new().enable_pllsai1(config).unwrap().voltage_range(VoltageRange::VRange2);
+        assert_eq!(result.err(), Some(Error::ClockOutOfRange(48_000_000)));
+
+        // 96 MHz / 4 fits Range 2
+        let clocks = ClockManager::new()
+            .enable_pllsai1(config.pllr(PLLDiv::Div4))
+            .unwrap()
+            .voltage_range(VoltageRange::VRange2)
+            .unwrap();
+        assert_eq!(current_range(), VoltageRange::VRange2);
+        assert_eq!(clocks.freeze().pllsai1r(), Some(24_000_000));
+    }
+}
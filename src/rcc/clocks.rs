@@ -0,0 +1,263 @@
+//! Frozen clock tree frequencies.
+//!
+//! `ClockManager::freeze` ends clock configuration and returns a `Clocks`, which
+//! peripheral constructors take to work out their dividers. All frequencies are
+//! in Hz.
+
+use super::kernel::{
+    AdcClock, Clk48Clock, DfsdmClock, I2cClock, KernelClock, LptimClock, SaiClock, SwpmiClock, UsartClock,
+};
+
+/// Peripherals with a kernel clock selectable in `CCIPR` or `CCIPR2`,
+/// independent of their bus clock.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Kernel {
+    Usart1,
+    Usart2,
+    Usart3,
+    Uart4,
+    Uart5,
+    Lpuart1,
+    I2c1,
+    I2c2,
+    I2c3,
+    I2c4,
+    Lptim1,
+    Lptim2,
+    Sai1,
+    Sai2,
+    /// 48 MHz clock for USB OTG FS, SDMMC and RNG.
+    Clk48,
+    Adc,
+    Swpmi1,
+    Dfsdm1,
+}
+
+impl Kernel {
+    pub(super) const COUNT: usize = 18;
+
+    pub(super) const ALL: [Kernel; Self::COUNT] = [
+        Self::Usart1,
+        Self::Usart2,
+        Self::Usart3,
+        Self::Uart4,
+        Self::Uart5,
+        Self::Lpuart1,
+        Self::I2c1,
+        Self::I2c2,
+        Self::I2c3,
+        Self::I2c4,
+        Self::Lptim1,
+        Self::Lptim2,
+        Self::Sai1,
+        Self::Sai2,
+        Self::Clk48,
+        Self::Adc,
+        Self::Swpmi1,
+        Self::Dfsdm1,
+    ];
+}
+
+/// Frequencies of the whole clock tree, fixed by `ClockManager::freeze`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Clocks {
+    pub(super) sysclk: u32,
+    pub(super) hclk: u32,
+    pub(super) pclk1: u32,
+    pub(super) pclk2: u32,
+    pub(super) timclk1: u32,
+    pub(super) timclk2: u32,
+    pub(super) msi: Option<u32>,
+    pub(super) hsi16: Option<u32>,
+    pub(super) hsi48: Option<u32>,
+    pub(super) hse: Option<u32>,
+    pub(super) lse: Option<u32>,
+    pub(super) lsi: Option<u32>,
+    pub(super) pllclk: Option<u32>,
+    pub(super) pllq: Option<u32>,
+    pub(super) pllp: Option<u32>,
+    // PLLP, PLLQ and PLLR outputs
+    pub(super) pllsai1: [Option<u32>; 3],
+    pub(super) pllsai2: [Option<u32>; 3],
+    pub(super) msi_pll_mode: bool,
+    pub(super) kernel: [Option<u32>; Kernel::COUNT],
+}
+
+impl Clocks {
+    /// System clock.
+    pub fn sysclk(&self) -> u32 {
+        self.sysclk
+    }
+
+    /// AHB bus, core and memory clock.
+    pub fn hclk(&self) -> u32 {
+        self.hclk
+    }
+
+    /// APB1 peripheral clock.
+    pub fn pclk1(&self) -> u32 {
+        self.pclk1
+    }
+
+    /// APB2 peripheral clock.
+    pub fn pclk2(&self) -> u32 {
+        self.pclk2
+    }
+
+    /// Clock of the timers on APB1 (TIM2 - TIM7, LPTIM1/2 bus side).
+    pub fn timclk1(&self) -> u32 {
+        self.timclk1
+    }
+
+    /// Clock of the timers on APB2 (TIM1, TIM8, TIM15 - TIM17).
+    pub fn timclk2(&self) -> u32 {
+        self.timclk2
+    }
+
+    pub fn msi(&self) -> Option<u32> {
+        self.msi
+    }
+
+    pub fn hsi16(&self) -> Option<u32> {
+        self.hsi16
+    }
+
+    pub fn hsi48(&self) -> Option<u32> {
+        self.hsi48
+    }
+
+    pub fn hse(&self) -> Option<u32> {
+        self.hse
+    }
+
+    /// LSE frequency, or `None` if it is off or the clock security system has
+    /// detected a failure.
+    pub fn lse(&self) -> Option<u32> {
+        self.lse
+    }
+
+    pub fn lsi(&self) -> Option<u32> {
+        self.lsi
+    }
+
+    /// Main PLL PLLR output, if running.
+    pub fn pllclk(&self) -> Option<u32> {
+        self.pllclk
+    }
+
+    /// Main PLL PLLQ output, if enabled.
+    pub fn pllq(&self) -> Option<u32> {
+        self.pllq
+    }
+
+    /// Main PLL PLLP output, if enabled.
+    pub fn pllp(&self) -> Option<u32> {
+        self.pllp
+    }
+
+    /// PLLSAI1 PLLP output (SAI clock), if enabled.
+    pub fn pllsai1p(&self) -> Option<u32> {
+        self.pllsai1[0]
+    }
+
+    /// PLLSAI1 PLLQ output (48 MHz clock), if enabled.
+    pub fn pllsai1q(&self) -> Option<u32> {
+        self.pllsai1[1]
+    }
+
+    /// PLLSAI1 PLLR output (ADC clock), if enabled.
+    pub fn pllsai1r(&self) -> Option<u32> {
+        self.pllsai1[2]
+    }
+
+    /// PLLSAI2 PLLP output (SAI clock), if enabled.
+    pub fn pllsai2p(&self) -> Option<u32> {
+        self.pllsai2[0]
+    }
+
+    /// PLLSAI2 PLLR output (ADC clock), if enabled.
+    pub fn pllsai2r(&self) -> Option<u32> {
+        self.pllsai2[2]
+    }
+
+    /// Whether the MSI is trimmed against the LSE (`MSIPLLEN`).
+    pub fn msi_pll_mode(&self) -> bool {
+        self.msi_pll_mode
+    }
+
+    /// Kernel clock of a peripheral, or `None` if its selected source isn't
+    /// running.
+    pub fn kernel(&self, peripheral: Kernel) -> Option<u32> {
+        self.kernel[peripheral as usize]
+    }
+
+    // Work out the kernel clocks from the `CCIPR` and `CCIPR2` selections
+    pub(super) fn select_kernels(&mut self, ccipr: u32, ccipr2: u32) {
+        for kernel in Kernel::ALL {
+            let selection = KernelClock::decode(kernel, ccipr, ccipr2);
+            self.kernel[kernel as usize] = selection.and_then(|selection| self.source(selection));
+        }
+    }
+
+    // Frequency of a kernel clock selection, if its source is running
+    fn source(&self, selection: KernelClock) -> Option<u32> {
+        let usart = |source, pclk| match source {
+            UsartClock::Pclk => Some(pclk),
+            UsartClock::SysClk => Some(self.sysclk),
+            UsartClock::Hsi16 => self.hsi16,
+            UsartClock::Lse => self.lse,
+        };
+        let i2c = |source| match source {
+            I2cClock::Pclk1 => Some(self.pclk1),
+            I2cClock::SysClk => Some(self.sysclk),
+            I2cClock::Hsi16 => self.hsi16,
+        };
+        let lptim = |source| match source {
+            LptimClock::Pclk1 => Some(self.pclk1),
+            LptimClock::Lsi => self.lsi,
+            LptimClock::Hsi16 => self.hsi16,
+            LptimClock::Lse => self.lse,
+        };
+        let sai = |source| match source {
+            SaiClock::PllSai1P => self.pllsai1p(),
+            SaiClock::PllSai2P => self.pllsai2p(),
+            SaiClock::PllP => self.pllp,
+            SaiClock::External => None,
+        };
+
+        match selection {
+            KernelClock::Usart1(source) => usart(source, self.pclk2),
+            KernelClock::Usart2(source)
+            | KernelClock::Usart3(source)
+            | KernelClock::Uart4(source)
+            | KernelClock::Uart5(source)
+            | KernelClock::Lpuart1(source) => usart(source, self.pclk1),
+            KernelClock::I2c1(source)
+            | KernelClock::I2c2(source)
+            | KernelClock::I2c3(source)
+            | KernelClock::I2c4(source) => i2c(source),
+            KernelClock::Lptim1(source) | KernelClock::Lptim2(source) => lptim(source),
+            KernelClock::Sai1(source) | KernelClock::Sai2(source) => sai(source),
+            KernelClock::Clk48(source) => match source {
+                Clk48Clock::Hsi48 => self.hsi48,
+                Clk48Clock::PllQ => self.pllq,
+                Clk48Clock::Msi => self.msi,
+                Clk48Clock::PllSai1Q => self.pllsai1q(),
+            },
+            KernelClock::Adc(source) => match source {
+                AdcClock::PllSai1R => self.pllsai1r(),
+                AdcClock::PllSai2R => self.pllsai2r(),
+                AdcClock::SysClk => Some(self.sysclk),
+                AdcClock::Off => None,
+            },
+            KernelClock::Swpmi1(source) => match source {
+                SwpmiClock::Pclk1 => Some(self.pclk1),
+                SwpmiClock::Hsi16 => self.hsi16,
+            },
+            KernelClock::Dfsdm1(source) => match source {
+                DfsdmClock::Pclk2 => Some(self.pclk2),
+                DfsdmClock::SysClk => Some(self.sysclk),
+            },
+        }
+    }
+}
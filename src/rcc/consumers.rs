@@ -0,0 +1,160 @@
+//! Oscillator users.
+//!
+//! An oscillator is only turned off once nothing runs from it any more. The users
+//! are read back from the clock configuration registers, so they can't get out
+//! of step with the hardware.
+
+use crate::access;
+
+use super::kernel::{self, KernelClock};
+use super::Kernel;
+
+/// Oscillators whose users are tracked.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Oscillator {
+    Msi,
+    Hsi16,
+    /// The 48 MHz RC oscillator, only used as a kernel clock.
+    Hsi48,
+    Hse,
+    Lse,
+    Lsi,
+}
+
+/// Something that keeps an oscillator on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Consumer {
+    /// The system clock.
+    SysClk,
+    /// The main PLL or PLLSAI1/PLLSAI2, which share one input.
+    PllInput,
+    /// A peripheral kernel clock selected in `CCIPR` or `CCIPR2`.
+    Kernel(Kernel),
+    /// The RTC (`BDCR.RTCSEL`).
+    Rtc,
+    /// The clock used on wakeup from Stop mode (`CFGR.STOPWUCK`), once selected
+    /// with `ClockManager::stop_wakeup_clock`.
+    Wakeup,
+    /// MSI PLL mode, trimming the MSI against the LSE.
+    MsiPll,
+    /// The LSE clock security system, which runs from the LSI.
+    LseCss,
+}
+
+impl Consumer {
+    // Bit in `Consumers`: the kernels first, then the rest
+    fn bit(self) -> u32 {
+        let index = match self {
+            Self::Kernel(kernel) => kernel as usize,
+            Self::SysClk => Kernel::COUNT,
+            Self::PllInput => Kernel::COUNT + 1,
+            Self::Rtc => Kernel::COUNT + 2,
+            Self::Wakeup => Kernel::COUNT + 3,
+            Self::MsiPll => Kernel::COUNT + 4,
+            Self::LseCss => Kernel::COUNT + 5,
+        };
+        1 << index
+    }
+}
+
+const CONSUMERS: [Consumer; 6] = [
+    Consumer::SysClk,
+    Consumer::PllInput,
+    Consumer::Rtc,
+    Consumer::Wakeup,
+    Consumer::MsiPll,
+    Consumer::LseCss,
+];
+
+/// The users of an oscillator, from `ClockManager::consumers`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Consumers(u32);
+
+impl Consumers {
+    /// Number of users.
+    pub fn count(&self) -> u32 {
+        self.0.count_ones()
+    }
+
+    /// Whether nothing runs from the oscillator, so it can be turned off.
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, consumer: Consumer) -> bool {
+        self.0 & consumer.bit() != 0
+    }
+
+    /// The users, kernel clocks first.
+    pub fn iter(&self) -> impl Iterator<Item = Consumer> + '_ {
+        Kernel::ALL
+            .into_iter()
+            .map(Consumer::Kernel)
+            .chain(CONSUMERS)
+            .filter(|consumer| self.contains(*consumer))
+    }
+
+    pub(super) fn add(&mut self, consumer: Consumer) {
+        self.0 |= consumer.bit();
+    }
+}
+
+// Oscillator feeding the PLLs, whether or not they are running
+pub(super) fn pll_input() -> Option<Oscillator> {
+    let rcc = access::rcc();
+    match rcc.pllcfgr().read().pllsrc().bits() {
+        0b01 => Some(Oscillator::Msi),
+        0b10 => Some(Oscillator::Hsi16),
+        0b11 => Some(Oscillator::Hse),
+        _ => None,
+    }
+}
+
+// Users of `oscillator` visible in the RCC registers
+pub(super) fn consumers(oscillator: Oscillator) -> Consumers {
+    let rcc = access::rcc();
+    let cr = rcc.cr().read();
+    let cfgr = rcc.cfgr().read();
+    let bdcr = rcc.bdcr().read();
+    let mut consumers = Consumers::default();
+
+    let sysclk = match cfgr.sws().bits() {
+        0b00 => Some(Oscillator::Msi),
+        0b01 => Some(Oscillator::Hsi16),
+        0b10 => Some(Oscillator::Hse),
+        _ => None,
+    };
+    if sysclk == Some(oscillator) {
+        consumers.add(Consumer::SysClk);
+    }
+
+    let pll_on = cr.pllon().bit() || cr.pllsai1on().bit() || cr.pllsai2on().bit();
+    if pll_on && pll_input() == Some(oscillator) {
+        consumers.add(Consumer::PllInput);
+    }
+
+    let (ccipr, ccipr2) = kernel::registers();
+    for kernel in Kernel::ALL {
+        let selection = KernelClock::decode(kernel, ccipr, ccipr2);
+        if selection.and_then(|selection| selection.oscillator()) == Some(oscillator) {
+            consumers.add(Consumer::Kernel(kernel));
+        }
+    }
+
+    let rtc = match bdcr.rtcsel().bits() {
+        0b01 => Some(Oscillator::Lse),
+        0b10 => Some(Oscillator::Lsi),
+        0b11 => Some(Oscillator::Hse),
+        _ => None,
+    };
+    if bdcr.rtcen().bit() && rtc == Some(oscillator) {
+        consumers.add(Consumer::Rtc);
+    }
+
+    match oscillator {
+        Oscillator::Lse if cr.msipllen().bit() => consumers.add(Consumer::MsiPll),
+        Oscillator::Lsi if bdcr.lsecsson().bit() => consumers.add(Consumer::LseCss),
+        _ => {}
+    }
+    consumers
+}
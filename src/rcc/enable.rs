@@ -0,0 +1,189 @@
+//! Peripheral bus clock enables and resets.
+//!
+//! Every peripheral with a bit in the `AHBxENR`/`APBxENR` registers implements
+//! `Enable`, and `Reset` if it also has a bit in `AHBxRSTR`/`APBxRSTR`:
+//!
+//! ```ignore
+//! use stm32l4_hal::rcc::{Enable, Reset};
+//!
+//! pac::USART2::enable();
+//! pac::USART2::reset();
+//! ```
+//!
+//! The enable and reset registers are shared between peripherals, so only the
+//! peripheral's own bit is changed.
+
+use crate::access;
+use crate::pac;
+
+/// Peripherals with a bus clock enable bit.
+pub trait Enable {
+    /// Enable the bus clock. The peripheral can be accessed as soon as this
+    /// returns.
+    fn enable();
+
+    /// Disable the bus clock.
+    fn disable();
+
+    fn is_enabled() -> bool;
+}
+
+/// Peripherals with a reset bit.
+pub trait Reset {
+    /// Pulse the reset, putting every register back to its reset value.
+    fn reset();
+}
+
+macro_rules! bus {
+    ($($periph:ident: ($enr:ident, $en:ident $(, $rstr:ident, $rst:ident)?),)+) => {
+        $(
+            impl Enable for pac::$periph {
+                fn enable() {
+                    let rcc = access::rcc();
+                    rcc.$enr().modify(|_,w| w.$en().set_bit());
+                    // The clock takes two bus cycles to start; reading the enable
+                    // back waits them out
+                    let _ = rcc.$enr().read().$en().bit();
+                }
+
+                fn disable() {
+                    let rcc = access::rcc();
+                    rcc.$enr().modify(|_,w| w.$en().clear_bit());
+                }
+
+                fn is_enabled() -> bool {
+                    let rcc = access::rcc();
+                    rcc.$enr().read().$en().bit_is_set()
+                }
+            }
+
+            $(
+                impl Reset for pac::$periph {
+                    fn reset() {
+                        let rcc = access::rcc();
+                        rcc.$rstr().modify(|_,w| w.$rst().set_bit());
+                        rcc.$rstr().modify(|_,w| w.$rst().clear_bit());
+                    }
+                }
+            )?
+        )+
+    };
+}
+
+// AHB1
+bus! {
+    DMA1: (ahb1enr, dma1en, ahb1rstr, dma1rst),
+    DMA2: (ahb1enr, dma2en, ahb1rstr, dma2rst),
+    FLASH: (ahb1enr, flashen, ahb1rstr, flashrst),
+    CRC: (ahb1enr, crcen, ahb1rstr, crcrst),
+    TSC: (ahb1enr, tscen, ahb1rstr, tscrst),
+    DMA2D: (ahb1enr, dma2den, ahb1rstr, dma2drst),
+}
+
+// AHB2. The three ADCs share one clock and reset.
+bus! {
+    GPIOA: (ahb2enr, gpioaen, ahb2rstr, gpioarst),
+    GPIOB: (ahb2enr, gpioben, ahb2rstr, gpiobrst),
+    GPIOC: (ahb2enr, gpiocen, ahb2rstr, gpiocrst),
+    GPIOD: (ahb2enr, gpioden, ahb2rstr, gpiodrst),
+    GPIOE: (ahb2enr, gpioeen, ahb2rstr, gpioerst),
+    GPIOF: (ahb2enr, gpiofen, ahb2rstr, gpiofrst),
+    GPIOG: (ahb2enr, gpiogen, ahb2rstr, gpiogrst),
+    GPIOH: (ahb2enr, gpiohen, ahb2rstr, gpiohrst),
+    GPIOI: (ahb2enr, gpioien, ahb2rstr, gpioirst),
+    OTG_FS_GLOBAL: (ahb2enr, otgfsen, ahb2rstr, otgfsrst),
+    ADC_COMMON: (ahb2enr, adcen, ahb2rstr, adcrst),
+    DCMI: (ahb2enr, dcmien, ahb2rstr, dcmirst),
+    AES: (ahb2enr, aesen, ahb2rstr, aesrst),
+    HASH: (ahb2enr, hash1en, ahb2rstr, hash1rst),
+    RNG: (ahb2enr, rngen, ahb2rstr, rngrst),
+}
+
+// AHB3
+bus! {
+    FMC: (ahb3enr, fmcen, ahb3rstr, fmcrst),
+    QUADSPI: (ahb3enr, qspien, ahb3rstr, qspirst),
+}
+
+// APB1, register 1. The RTC and WWDG have no reset here; the RTC is reset with
+// the backup domain.
+bus! {
+    TIM2: (apb1enr1, tim2en, apb1rstr1, tim2rst),
+    TIM3: (apb1enr1, tim3en, apb1rstr1, tim3rst),
+    TIM4: (apb1enr1, tim4en, apb1rstr1, tim4rst),
+    TIM5: (apb1enr1, tim5en, apb1rstr1, tim5rst),
+    TIM6: (apb1enr1, tim6en, apb1rstr1, tim6rst),
+    TIM7: (apb1enr1, tim7en, apb1rstr1, tim7rst),
+    LCD: (apb1enr1, lcden, apb1rstr1, lcdrst),
+    RTC: (apb1enr1, rtcapben),
+    WWDG: (apb1enr1, wwdgen),
+    SPI2: (apb1enr1, spi2en, apb1rstr1, spi2rst),
+    SPI3: (apb1enr1, spi3en, apb1rstr1, spi3rst),
+    USART2: (apb1enr1, usart2en, apb1rstr1, usart2rst),
+    USART3: (apb1enr1, usart3en, apb1rstr1, usart3rst),
+    UART4: (apb1enr1, uart4en, apb1rstr1, uart4rst),
+    UART5: (apb1enr1, uart5en, apb1rstr1, uart5rst),
+    I2C1: (apb1enr1, i2c1en, apb1rstr1, i2c1rst),
+    I2C2: (apb1enr1, i2c2en, apb1rstr1, i2c2rst),
+    I2C3: (apb1enr1, i2c3en, apb1rstr1, i2c3rst),
+    CRS: (apb1enr1, crsen, apb1rstr1, crsrst),
+    CAN1: (apb1enr1, can1en, apb1rstr1, can1rst),
+    CAN2: (apb1enr1, can2en, apb1rstr1, can2rst),
+    PWR: (apb1enr1, pwren, apb1rstr1, pwrrst),
+    DAC: (apb1enr1, dac1en, apb1rstr1, dac1rst),
+    OPAMP: (apb1enr1, opampen, apb1rstr1, opamprst),
+    LPTIM1: (apb1enr1, lptim1en, apb1rstr1, lptim1rst),
+}
+
+// APB1, register 2
+bus! {
+    LPUART1: (apb1enr2, lpuart1en, apb1rstr2, lpuart1rst),
+    I2C4: (apb1enr2, i2c4en, apb1rstr2, i2c4rst),
+    SWPMI1: (apb1enr2, swpmi1en, apb1rstr2, swpmi1rst),
+    LPTIM2: (apb1enr2, lptim2en, apb1rstr2, lptim2rst),
+}
+
+// APB2. The firewall can only be enabled, and has no reset.
+bus! {
+    SYSCFG: (apb2enr, syscfgen, apb2rstr, syscfgrst),
+    FIREWALL: (apb2enr, firewallen),
+    SDMMC1: (apb2enr, sdmmcen, apb2rstr, sdmmcrst),
+    TIM1: (apb2enr, tim1en, apb2rstr, tim1rst),
+    SPI1: (apb2enr, spi1en, apb2rstr, spi1rst),
+    TIM8: (apb2enr, tim8en, apb2rstr, tim8rst),
+    USART1: (apb2enr, usart1en, apb2rstr, usart1rst),
+    TIM15: (apb2enr, tim15en, apb2rstr, tim15rst),
+    TIM16: (apb2enr, tim16en, apb2rstr, tim16rst),
+    TIM17: (apb2enr, tim17en, apb2rstr, tim17rst),
+    SAI1: (apb2enr, sai1en, apb2rstr, sai1rst),
+    SAI2: (apb2enr, sai2en, apb2rstr, sai2rst),
+    DFSDM: (apb2enr, dfsdmen, apb2rstr, dfsdmrst),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::sim::{self, *};
+    use crate::access::Periph;
+
+    #[test]
+    fn enable_and_reset() {
+        sim::reset();
+        pac::USART2::enable();
+        pac::LPUART1::enable();
+        pac::TIM6::enable();
+        assert!(pac::USART2::is_enabled());
+        assert!(!pac::USART3::is_enabled());
+        assert_eq!(sim::read(Periph::Rcc, RCC_APB1ENR1), (1 << 17) | (1 << 4));
+        assert_eq!(sim::read(Periph::Rcc, RCC_APB1ENR2), 1);
+
+        pac::USART2::disable();
+        assert_eq!(sim::read(Periph::Rcc, RCC_APB1ENR1), 1 << 4);
+
+        sim::clear_writes();
+        pac::LPUART1::reset();
+        let resets = sim::writes_to(Periph::Rcc, RCC_APB1RSTR2);
+        assert_eq!(resets.iter().map(|w| w.new).collect::<Vec<_>>(), [1, 0]);
+        assert!(sim::writes_to(Periph::Rcc, RCC_APB1RSTR1).is_empty());
+    }
+}
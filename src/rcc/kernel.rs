@@ -0,0 +1,307 @@
+//! Peripheral kernel clock selection.
+//!
+//! USART, LPUART, I2C, LPTIM, SAI, ADC, SWPMI, DFSDM and the 48 MHz clock can
+//! run from a clock other than their bus clock. The selection lives in
+//! `RCC_CCIPR`, and for I2C4 in `RCC_CCIPR2`, and is made with
+//! `ClockManager::kernel_clock`. `Clocks::kernel` then gives the frequency the
+//! peripheral actually runs at.
+
+use crate::access;
+
+use super::{Kernel, Oscillator};
+
+// A selection enum with its field encoding
+macro_rules! source {
+    ($(#[$doc:meta])* $name:ident { $($(#[$vdoc:meta])* $variant:ident = $bits:literal,)+ }) => {
+        $(#[$doc])*
+        #[derive(Copy, Clone, Debug, PartialEq)]
+        pub enum $name {
+            $($(#[$vdoc])* $variant = $bits,)+
+        }
+
+        impl $name {
+            fn from_bits(bits: u32) -> Option<Self> {
+                match bits {
+                    $($bits => Some(Self::$variant),)+
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+source! {
+    /// USARTxSEL, UARTxSEL and LPUART1SEL.
+    UsartClock {
+        /// PCLK2 for USART1, PCLK1 for the others.
+        Pclk = 0b00,
+        SysClk = 0b01,
+        Hsi16 = 0b10,
+        Lse = 0b11,
+    }
+}
+
+source! {
+    /// I2CxSEL.
+    I2cClock {
+        Pclk1 = 0b00,
+        SysClk = 0b01,
+        Hsi16 = 0b10,
+    }
+}
+
+source! {
+    /// LPTIMxSEL.
+    LptimClock {
+        Pclk1 = 0b00,
+        Lsi = 0b01,
+        Hsi16 = 0b10,
+        Lse = 0b11,
+    }
+}
+
+source! {
+    /// SAIxSEL.
+    SaiClock {
+        PllSai1P = 0b00,
+        PllSai2P = 0b01,
+        PllP = 0b10,
+        /// The SAI_EXTCLK pin. Its frequency isn't known, so `Clocks::kernel`
+        /// reports `None`.
+        External = 0b11,
+    }
+}
+
+source! {
+    /// CLK48SEL, the 48 MHz clock of USB OTG FS, SDMMC and RNG.
+    Clk48Clock {
+        Hsi48 = 0b00,
+        PllSai1Q = 0b01,
+        PllQ = 0b10,
+        Msi = 0b11,
+    }
+}
+
+source! {
+    /// ADCSEL.
+    AdcClock {
+        /// No clock. The ADC can still run from HCLK (`ADC_CCR.CKMODE`).
+        Off = 0b00,
+        PllSai1R = 0b01,
+        PllSai2R = 0b10,
+        SysClk = 0b11,
+    }
+}
+
+source! {
+    /// SWPMI1SEL.
+    SwpmiClock {
+        Pclk1 = 0b0,
+        Hsi16 = 0b1,
+    }
+}
+
+source! {
+    /// DFSDMSEL.
+    DfsdmClock {
+        Pclk2 = 0b0,
+        SysClk = 0b1,
+    }
+}
+
+/// The kernel clock selection of one peripheral.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KernelClock {
+    Usart1(UsartClock),
+    Usart2(UsartClock),
+    Usart3(UsartClock),
+    Uart4(UsartClock),
+    Uart5(UsartClock),
+    Lpuart1(UsartClock),
+    I2c1(I2cClock),
+    I2c2(I2cClock),
+    I2c3(I2cClock),
+    /// Selected in `CCIPR2`.
+    I2c4(I2cClock),
+    Lptim1(LptimClock),
+    Lptim2(LptimClock),
+    Sai1(SaiClock),
+    Sai2(SaiClock),
+    Clk48(Clk48Clock),
+    Adc(AdcClock),
+    Swpmi1(SwpmiClock),
+    Dfsdm1(DfsdmClock),
+}
+
+// Where a selection lives: `CCIPR2` or `CCIPR`, shift and width
+struct Field {
+    ccipr2: bool,
+    shift: u32,
+    width: u32,
+}
+
+impl Field {
+    fn of(kernel: Kernel) -> Self {
+        let (ccipr2, shift, width) = match kernel {
+            Kernel::Usart1 => (false, 0, 2),
+            Kernel::Usart2 => (false, 2, 2),
+            Kernel::Usart3 => (false, 4, 2),
+            Kernel::Uart4 => (false, 6, 2),
+            Kernel::Uart5 => (false, 8, 2),
+            Kernel::Lpuart1 => (false, 10, 2),
+            Kernel::I2c1 => (false, 12, 2),
+            Kernel::I2c2 => (false, 14, 2),
+            Kernel::I2c3 => (false, 16, 2),
+            Kernel::I2c4 => (true, 0, 2),
+            Kernel::Lptim1 => (false, 18, 2),
+            Kernel::Lptim2 => (false, 20, 2),
+            Kernel::Sai1 => (false, 22, 2),
+            Kernel::Sai2 => (false, 24, 2),
+            Kernel::Clk48 => (false, 26, 2),
+            Kernel::Adc => (false, 28, 2),
+            Kernel::Swpmi1 => (false, 30, 1),
+            Kernel::Dfsdm1 => (false, 31, 1),
+        };
+        Field { ccipr2, shift, width }
+    }
+
+    fn mask(&self) -> u32 {
+        ((1 << self.width) - 1) << self.shift
+    }
+}
+
+impl KernelClock {
+    /// The peripheral this selection is for.
+    pub fn kernel(&self) -> Kernel {
+        match self {
+            Self::Usart1(_) => Kernel::Usart1,
+            Self::Usart2(_) => Kernel::Usart2,
+            Self::Usart3(_) => Kernel::Usart3,
+            Self::Uart4(_) => Kernel::Uart4,
+            Self::Uart5(_) => Kernel::Uart5,
+            Self::Lpuart1(_) => Kernel::Lpuart1,
+            Self::I2c1(_) => Kernel::I2c1,
+            Self::I2c2(_) => Kernel::I2c2,
+            Self::I2c3(_) => Kernel::I2c3,
+            Self::I2c4(_) => Kernel::I2c4,
+            Self::Lptim1(_) => Kernel::Lptim1,
+            Self::Lptim2(_) => Kernel::Lptim2,
+            Self::Sai1(_) => Kernel::Sai1,
+            Self::Sai2(_) => Kernel::Sai2,
+            Self::Clk48(_) => Kernel::Clk48,
+            Self::Adc(_) => Kernel::Adc,
+            Self::Swpmi1(_) => Kernel::Swpmi1,
+            Self::Dfsdm1(_) => Kernel::Dfsdm1,
+        }
+    }
+
+    /// The oscillator the peripheral runs from directly, if any. Selections of
+    /// a PLL output depend on the PLL input instead.
+    pub fn oscillator(&self) -> Option<Oscillator> {
+        match *self {
+            Self::Usart1(source)
+            | Self::Usart2(source)
+            | Self::Usart3(source)
+            | Self::Uart4(source)
+            | Self::Uart5(source)
+            | Self::Lpuart1(source) => match source {
+                UsartClock::Hsi16 => Some(Oscillator::Hsi16),
+                UsartClock::Lse => Some(Oscillator::Lse),
+                _ => None,
+            },
+            Self::I2c1(source) | Self::I2c2(source) | Self::I2c3(source) | Self::I2c4(source) => {
+                (source == I2cClock::Hsi16).then_some(Oscillator::Hsi16)
+            }
+            Self::Lptim1(source) | Self::Lptim2(source) => match source {
+                LptimClock::Lsi => Some(Oscillator::Lsi),
+                LptimClock::Hsi16 => Some(Oscillator::Hsi16),
+                LptimClock::Lse => Some(Oscillator::Lse),
+                LptimClock::Pclk1 => None,
+            },
+            Self::Clk48(source) => match source {
+                Clk48Clock::Hsi48 => Some(Oscillator::Hsi48),
+                Clk48Clock::Msi => Some(Oscillator::Msi),
+                _ => None,
+            },
+            Self::Swpmi1(source) => (source == SwpmiClock::Hsi16).then_some(Oscillator::Hsi16),
+            Self::Sai1(_) | Self::Sai2(_) | Self::Adc(_) | Self::Dfsdm1(_) => None,
+        }
+    }
+
+    // Field value of the selection
+    fn bits(&self) -> u32 {
+        match *self {
+            Self::Usart1(source)
+            | Self::Usart2(source)
+            | Self::Usart3(source)
+            | Self::Uart4(source)
+            | Self::Uart5(source)
+            | Self::Lpuart1(source) => source as u32,
+            Self::I2c1(source) | Self::I2c2(source) | Self::I2c3(source) | Self::I2c4(source) => source as u32,
+            Self::Lptim1(source) | Self::Lptim2(source) => source as u32,
+            Self::Sai1(source) | Self::Sai2(source) => source as u32,
+            Self::Clk48(source) => source as u32,
+            Self::Adc(source) => source as u32,
+            Self::Swpmi1(source) => source as u32,
+            Self::Dfsdm1(source) => source as u32,
+        }
+    }
+
+    // Selection of `kernel` in `ccipr` and `ccipr2`. `None` for a reserved value.
+    pub(super) fn decode(kernel: Kernel, ccipr: u32, ccipr2: u32) -> Option<Self> {
+        let field = Field::of(kernel);
+        let register = if field.ccipr2 { ccipr2 } else { ccipr };
+        let bits = (register & field.mask()) >> field.shift;
+
+        let usart = || UsartClock::from_bits(bits);
+        let i2c = || I2cClock::from_bits(bits);
+        let lptim = || LptimClock::from_bits(bits);
+        let sai = || SaiClock::from_bits(bits);
+        match kernel {
+            Kernel::Usart1 => usart().map(Self::Usart1),
+            Kernel::Usart2 => usart().map(Self::Usart2),
+            Kernel::Usart3 => usart().map(Self::Usart3),
+            Kernel::Uart4 => usart().map(Self::Uart4),
+            Kernel::Uart5 => usart().map(Self::Uart5),
+            Kernel::Lpuart1 => usart().map(Self::Lpuart1),
+            Kernel::I2c1 => i2c().map(Self::I2c1),
+            Kernel::I2c2 => i2c().map(Self::I2c2),
+            Kernel::I2c3 => i2c().map(Self::I2c3),
+            Kernel::I2c4 => i2c().map(Self::I2c4),
+            Kernel::Lptim1 => lptim().map(Self::Lptim1),
+            Kernel::Lptim2 => lptim().map(Self::Lptim2),
+            Kernel::Sai1 => sai().map(Self::Sai1),
+            Kernel::Sai2 => sai().map(Self::Sai2),
+            Kernel::Clk48 => Clk48Clock::from_bits(bits).map(Self::Clk48),
+            Kernel::Adc => AdcClock::from_bits(bits).map(Self::Adc),
+            Kernel::Swpmi1 => SwpmiClock::from_bits(bits).map(Self::Swpmi1),
+            Kernel::Dfsdm1 => DfsdmClock::from_bits(bits).map(Self::Dfsdm1),
+        }
+    }
+}
+
+// `CCIPR` and `CCIPR2`
+pub(super) fn registers() -> (u32, u32) {
+    let ccipr = access::rcc().ccipr().read().bits();
+    (ccipr, access::rcc_ext().ccipr2().read())
+}
+
+// Current selection of `kernel`
+pub(super) fn current(kernel: Kernel) -> Option<KernelClock> {
+    let (ccipr, ccipr2) = registers();
+    KernelClock::decode(kernel, ccipr, ccipr2)
+}
+
+// Write `selection` to its field
+pub(super) fn select(selection: KernelClock) {
+    let field = Field::of(selection.kernel());
+    let mask = field.mask();
+    let bits = selection.bits() << field.shift;
+
+    if field.ccipr2 {
+        access::rcc_ext().ccipr2().modify(|ccipr2| (ccipr2 & !mask) | bits);
+    } else {
+        let rcc = access::rcc();
+        rcc.ccipr().modify(|r, w| unsafe { w.bits((r.bits() & !mask) | bits) });
+    }
+}
@@ -3,7 +3,12 @@ use cortex_m::interrupt::Mutex;
 use stm32l4::stm32l4x6::interrupt;
 
 use crate::pac::tim1::{arr, cr1, psc, sr};
-use crate::pac::{RCC};
+use crate::access;
+use crate::pac;
+use crate::rcc::{Clocks, Enable};
+
+/// TIM6 counter frequency.
+pub const TICK_HZ: u32 = 1_000_000;
 
 //static G_TIM: Mutex<RefCell<Option<Timer>>> = Mutex::new(RefCell::new(None));
 
@@ -12,25 +17,27 @@ pub struct Timer {
 }
 
 impl Timer {
-    pub fn new() -> Self
+    /// Set up TIM6 to count at `TICK_HZ` from the APB1 timer clock, with the
+    /// update interrupt enabled.
+    pub fn new(clocks: &Clocks) -> Self
     {
-        // TODO: Get the system clock
+        // The counter runs at timclk / (PSC + 1). Below `TICK_HZ` it runs at timclk.
+        let psc = (clocks.timclk1() / TICK_HZ).max(1) - 1;
 
         unsafe {
             // Enable the Interrupt NVIC
-            cortex_m::peripheral::NVIC::unmask(stm32l4::stm32l4x6::interrupt::TIM6_DACUNDER);
+            access::unmask(crate::pac::Interrupt::TIM6_DACUNDER);
         }
 
         // Enable the RCC peripheral clock
-        let rcc = unsafe { &(*RCC::ptr()) };
-        if rcc.apb1enr1().read().tim6en().bit_is_clear() {
-            rcc.apb1enr1().write(|w| w.tim6en().set_bit());
+        if !pac::TIM6::is_enabled() {
+            pac::TIM6::enable();
         }
 
-        let tim6 = unsafe{ &(*crate::pac::TIM6::ptr()) };
+        let tim6 = access::tim6();
         unsafe {
             // Write the prescaler
-            tim6.psc().write(|w| w.bits(16));
+            tim6.psc().write(|w| w.bits(psc));
 
             // Write the auto-reload
             tim6.arr().write(|w| w.bits(0xFFFF));
@@ -50,7 +57,7 @@ impl Timer {
     }
 
     pub fn start(&self) {
-        let tim6 = unsafe{ &(*crate::pac::TIM6::ptr()) };
+        let tim6 = access::tim6();
         tim6.cr1().modify(|_,w| w.cen().bit(true));
     }
 }
@@ -59,6 +66,53 @@ impl Timer {
 fn TIM6_DACUNDER()
 {
     // Clear the timer interrupt
-    let tim6 = unsafe{ &(*crate::pac::TIM6::ptr()) };
+    let tim6 = access::tim6();
     tim6.sr().write(|w| w.uif().clear());
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::sim::{self, *};
+    use crate::access::Periph;
+    use crate::pac::Interrupt;
+    use crate::rcc::ClockManager;
+
+    #[test]
+    fn new_sets_up_tim6() {
+        sim::reset();
+        let clocks = ClockManager::new().switch_to_hsi().unwrap().freeze();
+        let timer = Timer::new(&clocks);
+
+        // TIM6EN, with PWREN left on
+        assert_eq!(sim::read(Periph::Rcc, RCC_APB1ENR1), (1 << 28) | (1 << 4));
+        // 16 MHz / 16 = 1 MHz
+        assert_eq!(sim::read(Periph::Tim6, TIM_PSC), 15);
+        assert_eq!(sim::read(Periph::Tim6, TIM_ARR), 0xFFFF);
+        assert_eq!(sim::read(Periph::Tim6, TIM_DIER) & 1, 1);
+        assert!(sim::is_unmasked(Interrupt::TIM6_DACUNDER));
+
+        timer.start();
+        assert_eq!(sim::read(Periph::Tim6, TIM_CR1) & 1, 1);
+    }
+
+    #[test]
+    fn prescaler_follows_clock() {
+        sim::reset();
+        let solution = crate::rcc::solve_pll(4_000_000, 80_000_000, false).unwrap();
+        let clocks = ClockManager::new()
+            .enable_pll(solution.config)
+            .unwrap()
+            .switch_to_pll()
+            .unwrap()
+            .freeze();
+        Timer::new(&clocks);
+        assert_eq!(sim::read(Periph::Tim6, TIM_PSC), 79);
+
+        // 100 kHz MSI is below the tick rate
+        sim::reset();
+        let mut clocks = ClockManager::new();
+        clocks.update_msi_range(crate::rcc::MSIRange::Range0).unwrap();
+        Timer::new(&clocks.freeze());
+        assert_eq!(sim::read(Periph::Tim6, TIM_PSC), 0);
+    }
+}